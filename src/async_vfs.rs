@@ -0,0 +1,791 @@
+//! An async counterpart of [Vfs] for servers running on tokio, enabled by the `async` feature.
+use std::future::Future;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{self, Stream, StreamExt};
+use log::warn;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::atomic::is_atomic_tmp;
+use crate::vfs::{
+    is_not_found, resolve_in, validate_within, DirEntry, DomainOptions, FilesystemVfs, MemoryVfs, Result, Vfs, VfsErr,
+    VfsFile, VfsMetadata, VfsOpenOptions, VfsPath, DOMAINS_SUBDIR, DRAFTS_SUBDIR, ECMA_SUBDIR, RESOURCES_SUBDIR,
+    TMP_SUBDIR, VERSIONS_SUBDIR,
+};
+
+///How much [BlockingVfs] reads or writes on the blocking pool at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type AsyncReader = Box<dyn AsyncRead + Send + Unpin>;
+///The entries of a directory, see [AsyncVfs::read_dir]
+pub type AsyncReadDir = Pin<Box<dyn Stream<Item=VfsPath> + Send>>;
+///Every file under a directory, see [AsyncVfs::dir_stream]
+pub type AsyncDirStream<'a> = Pin<Box<dyn Stream<Item=Result<DirEntry>> + Send + 'a>>;
+
+///A file opened by an [AsyncVfs], the async version of [VfsFile]
+#[async_trait]
+pub trait AsyncVfsFile: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin {
+    fn path(&self) -> VfsPath;
+    ///Flushes the file and, for backends with durable storage, waits until its content has reached it
+    async fn sync_all(&mut self) -> Result<()> {
+        self.flush().await.map_err(VfsErr::Io)
+    }
+}
+
+///The async version of [Vfs], with the same layout and path rules.
+///Paths are still [VfsPath]s from [AsyncVfs::resolve], only the methods which do I/O are async.
+///Implemented for [FilesystemVfs] and [MemoryVfs], any other [Vfs] can be wrapped in a [BlockingVfs].
+#[async_trait]
+pub trait AsyncVfs: Send + Sync {
+    ///See [Vfs::root]
+    fn root(&self) -> &PathBuf;
+    ///See [Vfs::resolve]
+    fn resolve(&self, child: &str) -> Result<VfsPath> {
+        resolve_in(self.root(), child)
+    }
+    ///See [Vfs::check_path]
+    fn check_path(&self, path: &Path) -> Result<()> {
+        validate_within(self.root(), path)
+    }
+    fn version_dir(&self, service_id: i64, is_draft: bool, version: &str) -> Result<VfsPath> {
+        let subdir = if is_draft { DRAFTS_SUBDIR } else { VERSIONS_SUBDIR };
+        self.resolve(format!("{}/{}/{}", service_id, subdir, version).as_str())
+    }
+    fn schema_file(&self, service_id: i64, is_draft: bool, version: &str, file: &str) -> Result<VfsPath> {
+        self.version_dir(service_id, is_draft, version)?.join(file)
+    }
+    fn ecma_dir(&self, service_id: i64, is_draft: bool, version: &str) -> Result<VfsPath> {
+        self.version_dir(service_id, is_draft, version)?.join(ECMA_SUBDIR)
+    }
+    ///See [Vfs::lazy_dirs]
+    fn lazy_dirs(&self) -> bool {
+        false
+    }
+    fn domain_file(&self, domain: &str) -> Result<VfsPath> {
+        self.resolve(format!("{}/{}", DOMAINS_SUBDIR, domain).as_str())
+    }
+    ///See [Vfs::read_domain_file], this is on every request's path so each backend resolves it its own way
+    async fn read_domain_file(&self, domain: &str) -> Result<DomainOptions>;
+    ///See [Vfs::resource_dir]
+    async fn resource_dir(&self, service_id: i64) -> Result<VfsPath> {
+        let dir = self.resolve(format!("{}/{}", service_id, RESOURCES_SUBDIR).as_str())?;
+        if !self.lazy_dirs() {
+            self.create_dir_all(&dir).await?;
+        }
+        Ok(dir)
+    }
+    async fn resource_file(&self, service_id: i64, name: &str) -> Result<VfsPath> {
+        self.resource_dir(service_id).await?.join(name)
+    }
+    async fn read_resource_file(&self, service_id: i64, filename: &str) -> Result<AsyncReader> {
+        let file = self.resource_file(service_id, filename).await?;
+        self.read(file).await
+    }
+    ///See [Vfs::tmp_dir]
+    async fn tmp_dir(&self, service_id: i64) -> Result<VfsPath> {
+        let dir = self.resolve(format!("{}/{}", service_id, TMP_SUBDIR).as_str())?;
+        if !self.lazy_dirs() {
+            self.create_dir_all(&dir).await?;
+        }
+        Ok(dir)
+    }
+    async fn read(&self, file: VfsPath) -> Result<AsyncReader>;
+    async fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn AsyncVfsFile>>;
+    async fn read_dir(&self, dir: &VfsPath) -> Result<AsyncReadDir>;
+    ///See [Vfs::create_dir_all]
+    async fn create_dir_all(&self, dir: &VfsPath) -> Result<()>;
+    ///See [Vfs::create_dir]
+    async fn create_dir(&self, dir: &VfsPath) -> Result<()>;
+    ///See [Vfs::remove_dir]
+    async fn remove_dir(&self, dir: &VfsPath) -> Result<()>;
+    ///See [Vfs::remove_dir_all]
+    async fn remove_dir_all(&self, dir: &VfsPath) -> Result<()>;
+    ///See [Vfs::rename]
+    async fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()>;
+    async fn remove_file(&self, file: &VfsPath) -> Result<()>;
+    ///See [Vfs::copy]
+    async fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64>;
+    async fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata>;
+    ///See [Vfs::exists]
+    async fn exists(&self, path: &VfsPath) -> Result<bool> {
+        match self.metadata(path).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    ///The whole content of `file`
+    async fn read_to_end(&self, file: VfsPath) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        self.read(file).await?.read_to_end(&mut data).await.map_err(VfsErr::Io)?;
+        Ok(data)
+    }
+    ///See [Vfs::read_bytes]
+    async fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        Ok(Bytes::from(self.read_to_end(file).await?))
+    }
+    async fn read_schema_file(&self, service_id: i64, is_draft: bool, version: &str, filename: &str) -> Result<String> {
+        let file = self.schema_file(service_id, is_draft, version, filename)?;
+        String::from_utf8(self.read_bytes(file).await?.into()).map_err(VfsErr::Utf8)
+    }
+    ///The version's ECMA scripts, see [Vfs::read_ecma]
+    async fn read_ecma<'a>(&'a self, service_id: i64, is_draft: bool, version: &str) -> Result<AsyncDirStream<'a>> {
+        let dir = self.ecma_dir(service_id, is_draft, version)?;
+        self.dir_stream(dir).await
+    }
+    ///Every file under `dir` in the same order as [Vfs::dir_stream]
+    async fn dir_stream<'a>(&'a self, dir: VfsPath) -> Result<AsyncDirStream<'a>> {
+        walk(self, dir, None).await
+    }
+    ///[AsyncVfs::dir_stream] descending at most `depth` directories, see [DirStream::with_max_depth](crate::vfs::DirStream::with_max_depth)
+    async fn dir_stream_with_max_depth<'a>(&'a self, dir: VfsPath, depth: usize) -> Result<AsyncDirStream<'a>> {
+        walk(self, dir, Some(depth)).await
+    }
+}
+
+async fn walk<V>(vfs: &V, dir: VfsPath, max_depth: Option<usize>) -> Result<AsyncDirStream<'_>>
+    where
+        V: AsyncVfs + ?Sized,
+{
+    if let Err(e) = vfs.check_path(dir.as_path()) {
+        warn!("Path must be under the root, got {}", dir.as_path().to_string_lossy());
+        return Err(e);
+    }
+    let entries = vfs.read_dir(&dir).await?;
+    let mut walk = Walk {
+        vfs,
+        base: dir,
+        stack: vec![],
+        max_depth,
+    };
+    walk.push(entries).await;
+    Ok(Box::pin(stream::unfold(walk, Walk::next)))
+}
+
+///The state of an [AsyncVfs::dir_stream], it walks the tree in the same order as [DirStream](crate::vfs::DirStream)
+struct Walk<'a, V>
+    where
+        V: AsyncVfs + ?Sized,
+{
+    vfs: &'a V,
+    base: VfsPath,
+    stack: Vec<std::vec::IntoIter<VfsPath>>,
+    max_depth: Option<usize>,
+}
+
+impl<'a, V> Walk<'a, V>
+    where
+        V: AsyncVfs + ?Sized,
+{
+    async fn push(&mut self, entries: AsyncReadDir) {
+        let mut entries: Vec<_> = entries.collect().await;
+        entries.sort();
+        self.stack.push(entries.into_iter());
+    }
+
+    async fn next(mut self) -> Option<(Result<DirEntry>, Self)> {
+        loop {
+            let depth = self.stack.len();
+            let Some(path) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if self.vfs.check_path(path.as_path()).is_err() {
+                warn!(
+                    "Skipping path {} because it is not under the root",
+                    path.as_path().to_string_lossy()
+                );
+                continue;
+            }
+            //silently skip files that are not in the base directory and files being written by an AtomicWriter
+            let rel = match path.as_path().strip_prefix(self.base.as_path()) {
+                Ok(rel) if !rel.as_os_str().is_empty() && !is_atomic_tmp(path.as_path()) => rel.to_path_buf(),
+                _ => continue,
+            };
+            let metadata = match self.vfs.metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) => return Some((Err(e), self)),
+            };
+            if !metadata.is_dir() {
+                return Some((Ok(DirEntry { rel, path, metadata }), self));
+            }
+            if self.max_depth.is_some_and(|max| depth > max) {
+                continue;
+            }
+            match self.vfs.read_dir(&path).await {
+                Ok(entries) => self.push(entries).await,
+                Err(e) => return Some((Err(e), self)),
+            }
+        }
+    }
+}
+
+///Runs `f` with `vfs` on tokio's blocking thread pool
+async fn blocking<V, T, C>(vfs: V, f: C) -> Result<T>
+    where
+        V: Send + 'static,
+        T: Send + 'static,
+        C: FnOnce(V) -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(vfs)).await.map_err(join_err)?
+}
+
+fn join_err(e: tokio::task::JoinError) -> VfsErr {
+    VfsErr::Io(std::io::Error::other(e))
+}
+
+///Adapts any [Vfs] to [AsyncVfs] by running its calls on tokio's blocking thread pool with `spawn_blocking`.
+///Files are read and written in chunks as they're used, so reading a large file doesn't hold a thread until it's done.
+pub struct BlockingVfs<F>
+    where
+        F: Vfs + 'static,
+{
+    inner: Arc<F>,
+}
+
+impl<F> BlockingVfs<F>
+    where
+        F: Vfs + 'static,
+{
+    pub fn new(inner: Arc<F>) -> Self {
+        BlockingVfs { inner }
+    }
+    pub fn inner(&self) -> &Arc<F> {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<F> AsyncVfs for BlockingVfs<F>
+    where
+        F: Vfs + 'static,
+{
+    fn root(&self) -> &PathBuf {
+        self.inner.root()
+    }
+    fn resolve(&self, child: &str) -> Result<VfsPath> {
+        self.inner.resolve(child)
+    }
+    fn check_path(&self, path: &Path) -> Result<()> {
+        self.inner.check_path(path)
+    }
+    fn lazy_dirs(&self) -> bool {
+        self.inner.lazy_dirs()
+    }
+    async fn read_domain_file(&self, domain: &str) -> Result<DomainOptions> {
+        let domain = domain.to_string();
+        blocking(self.inner.clone(), move |vfs| vfs.read_domain_file(&domain)).await
+    }
+    async fn read(&self, file: VfsPath) -> Result<AsyncReader> {
+        let vfs = self.inner.clone();
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut input = match vfs.read(file) {
+                Ok(input) => input,
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = opened_tx.send(Ok(()));
+            loop {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let chunk = match input.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Ok(Bytes::from(buf))
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                //stops when the reader is dropped
+                if tx.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        opened_rx.await.map_err(|e| VfsErr::Io(std::io::Error::other(e)))??;
+        Ok(Box::new(ChannelReader {
+            chunks: rx,
+            chunk: Bytes::new(),
+        }))
+    }
+    async fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn AsyncVfsFile>> {
+        let path = file.clone();
+        let file = blocking(self.inner.clone(), move |vfs| vfs.open_with(file, opts)).await?;
+        Ok(Box::new(BlockingFile::new(path, file)))
+    }
+    async fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        blocking(self.inner.clone(), move |vfs| vfs.read_bytes(file)).await
+    }
+    async fn read_dir(&self, dir: &VfsPath) -> Result<AsyncReadDir> {
+        let dir = dir.clone();
+        let entries = blocking(self.inner.clone(), move |vfs| Ok(vfs.read_dir(&dir)?.collect::<Vec<_>>())).await?;
+        Ok(Box::pin(stream::iter(entries)))
+    }
+    async fn create_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.inner.clone(), move |vfs| vfs.create_dir_all(&dir)).await
+    }
+    async fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.inner.clone(), move |vfs| vfs.create_dir(&dir)).await
+    }
+    async fn remove_dir(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.inner.clone(), move |vfs| vfs.remove_dir(&dir)).await
+    }
+    async fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.inner.clone(), move |vfs| vfs.remove_dir_all(&dir)).await
+    }
+    async fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        let (from, to) = (from.clone(), to.clone());
+        blocking(self.inner.clone(), move |vfs| vfs.rename(&from, &to)).await
+    }
+    async fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        let file = file.clone();
+        blocking(self.inner.clone(), move |vfs| vfs.remove_file(&file)).await
+    }
+    async fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        let (from, to) = (from.clone(), to.clone());
+        blocking(self.inner.clone(), move |vfs| vfs.copy(&from, &to)).await
+    }
+    async fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata> {
+        let path = path.clone();
+        blocking(self.inner.clone(), move |vfs| vfs.metadata(&path)).await
+    }
+}
+
+///Reads the chunks sent by the blocking task [BlockingVfs::read] starts
+struct ChannelReader {
+    chunks: mpsc::Receiver<std::io::Result<Bytes>>,
+    ///What's left of the last chunk received
+    chunk: Bytes,
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        while self.chunk.is_empty() {
+            match ready!(self.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                //the end of the file
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.chunk.len().min(buf.remaining());
+        let data = self.chunk.split_to(n);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+///What a [BlockingFile] did on the blocking pool
+enum FileOp {
+    Read(std::io::Result<usize>),
+    Write(std::io::Result<()>),
+    Flush(std::io::Result<()>),
+    Seek(std::io::Result<u64>),
+}
+
+type FileTask = JoinHandle<(Box<dyn VfsFile>, Vec<u8>, FileOp)>;
+
+///A [VfsFile] opened by a [BlockingVfs]. Each read, write, flush and seek runs on the blocking pool, one at a time.
+///Like [tokio::fs::File] a write returns once the data has been handed over and any error writing it is returned by
+///the next operation, so `flush` before dropping the file to be sure everything was written.
+pub struct BlockingFile {
+    path: VfsPath,
+    ///[None] while an operation is running or if a task panicked with it
+    file: Option<Box<dyn VfsFile>>,
+    task: Option<FileTask>,
+    ///Read from the file but not yet returned, from `pos`
+    buf: Vec<u8>,
+    pos: usize,
+    write_err: Option<std::io::Error>,
+}
+
+impl BlockingFile {
+    fn new(path: VfsPath, file: Box<dyn VfsFile>) -> Self {
+        BlockingFile {
+            path,
+            file: Some(file),
+            task: None,
+            buf: vec![],
+            pos: 0,
+            write_err: None,
+        }
+    }
+
+    ///Waits for the running operation, if any, and returns its result. A failed write is kept for the next operation
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<FileOp>>> {
+        let Some(task) = self.task.as_mut() else {
+            return Poll::Ready(Ok(None));
+        };
+        let res = ready!(Pin::new(task).poll(cx));
+        self.task = None;
+        let (file, buf, op) = res.map_err(std::io::Error::other)?;
+        self.file = Some(file);
+        self.buf = buf;
+        self.pos = 0;
+        match op {
+            FileOp::Read(Ok(n)) => self.buf.truncate(n),
+            FileOp::Write(Err(e)) => {
+                self.write_err = Some(e);
+                return Poll::Ready(Ok(None));
+            }
+            _ => {}
+        }
+        Poll::Ready(Ok(Some(op)))
+    }
+
+    ///Runs `op` on the blocking pool, the file must be idle
+    fn start<O>(&mut self, op: O) -> std::io::Result<()>
+        where
+            O: FnOnce(&mut Box<dyn VfsFile>, &mut Vec<u8>) -> FileOp + Send + 'static,
+    {
+        if let Some(e) = self.write_err.take() {
+            return Err(e);
+        }
+        let mut file = self.file.take().ok_or_else(lost)?;
+        let mut buf = std::mem::take(&mut self.buf);
+        //data read ahead has to be given back first, the file's position is after it
+        let unread = (buf.len() - self.pos) as i64;
+        buf.clear();
+        self.pos = 0;
+        self.task = Some(tokio::task::spawn_blocking(move || {
+            if unread > 0 {
+                if let Err(e) = file.seek(SeekFrom::Current(-unread)) {
+                    return (file, buf, FileOp::Seek(Err(e)));
+                }
+            }
+            let op = op(&mut file, &mut buf);
+            (file, buf, op)
+        }));
+        Ok(())
+    }
+}
+
+fn lost() -> std::io::Error {
+    std::io::Error::other("the file was lost when an operation on it panicked")
+}
+
+impl AsyncRead for BlockingFile {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, dst: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match ready!(this.poll_idle(cx))? {
+                //the end of the file
+                Some(FileOp::Read(Ok(0))) => return Poll::Ready(Ok(())),
+                Some(FileOp::Read(Err(e)) | FileOp::Seek(Err(e)) | FileOp::Flush(Err(e))) => {
+                    return Poll::Ready(Err(e));
+                }
+                _ => {}
+            }
+            if this.pos < this.buf.len() {
+                let n = (this.buf.len() - this.pos).min(dst.remaining());
+                dst.put_slice(&this.buf[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if dst.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let len = dst.remaining().min(CHUNK_SIZE);
+            this.start(move |file, buf| {
+                buf.resize(len, 0);
+                FileOp::Read(file.read(buf))
+            })?;
+        }
+    }
+}
+
+impl AsyncWrite for BlockingFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, src: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(FileOp::Seek(Err(e)) | FileOp::Flush(Err(e))) = ready!(this.poll_idle(cx))? {
+            return Poll::Ready(Err(e));
+        }
+        let n = src.len().min(CHUNK_SIZE);
+        let data = src[..n].to_vec();
+        this.start(move |file, _| FileOp::Write(file.write_all(&data)))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match ready!(this.poll_idle(cx))? {
+                Some(FileOp::Flush(res)) => return Poll::Ready(res),
+                Some(FileOp::Seek(Err(e))) => return Poll::Ready(Err(e)),
+                _ => {}
+            }
+            this.start(|file, _| FileOp::Flush(file.flush()))?;
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for BlockingFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        if this.task.is_some() {
+            return Err(std::io::Error::other("another operation is running, call poll_complete first"));
+        }
+        this.start(move |file, _| FileOp::Seek(file.seek(position)))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            match ready!(this.poll_idle(cx))? {
+                Some(FileOp::Seek(res)) => return Poll::Ready(res),
+                Some(FileOp::Flush(Err(e))) => return Poll::Ready(Err(e)),
+                _ => {}
+            }
+            //no seek was started, return the current position
+            this.start(|file, _| FileOp::Seek(file.stream_position()))?;
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncVfsFile for BlockingFile {
+    fn path(&self) -> VfsPath {
+        self.path.clone()
+    }
+    async fn sync_all(&mut self) -> Result<()> {
+        self.flush().await.map_err(VfsErr::Io)?;
+        let mut file = self.file.take().ok_or_else(|| VfsErr::Io(lost()))?;
+        let (file, res) = tokio::task::spawn_blocking(move || {
+            let res = file.sync_all();
+            (file, res)
+        })
+        .await
+        .map_err(join_err)?;
+        self.file = Some(file);
+        res
+    }
+}
+
+///A file opened by the [AsyncVfs] of [FilesystemVfs]
+pub struct AsyncFsFile(File, VfsPath);
+
+#[async_trait]
+impl AsyncVfsFile for AsyncFsFile {
+    fn path(&self) -> VfsPath {
+        self.1.clone()
+    }
+    async fn sync_all(&mut self) -> Result<()> {
+        self.0.flush().await.map_err(VfsErr::Io)?;
+        self.0.sync_all().await.map_err(VfsErr::Io)
+    }
+}
+
+impl AsyncRead for AsyncFsFile {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncFsFile {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for AsyncFsFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.0).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.0).poll_complete(cx)
+    }
+}
+
+///Files are opened on the blocking pool and then used through [tokio::fs::File], the other calls run the [Vfs] ones
+///on the blocking pool
+#[async_trait]
+impl AsyncVfs for FilesystemVfs {
+    fn root(&self) -> &PathBuf {
+        Vfs::root(self)
+    }
+    fn lazy_dirs(&self) -> bool {
+        Vfs::lazy_dirs(self)
+    }
+    async fn read_domain_file(&self, domain: &str) -> Result<DomainOptions> {
+        let domain = domain.to_string();
+        blocking(self.clone(), move |vfs| Vfs::read_domain_file(&vfs, &domain)).await
+    }
+    async fn read(&self, file: VfsPath) -> Result<AsyncReader> {
+        let mut opts = VfsOpenOptions::new();
+        opts.read(true);
+        let file = blocking(self.clone(), move |vfs| vfs.open_file(&file, &opts)).await?;
+        Ok(Box::new(File::from_std(file)))
+    }
+    async fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn AsyncVfsFile>> {
+        let path = file.clone();
+        let file = blocking(self.clone(), move |vfs| vfs.open_file(&file, &opts)).await?;
+        Ok(Box::new(AsyncFsFile(File::from_std(file), path)))
+    }
+    async fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        blocking(self.clone(), move |vfs| Vfs::read_bytes(&vfs, file)).await
+    }
+    async fn read_dir(&self, dir: &VfsPath) -> Result<AsyncReadDir> {
+        let dir = dir.clone();
+        let entries = blocking(self.clone(), move |vfs| Ok(Vfs::read_dir(&vfs, &dir)?.collect::<Vec<_>>())).await?;
+        Ok(Box::pin(stream::iter(entries)))
+    }
+    async fn create_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.clone(), move |vfs| Vfs::create_dir_all(&vfs, &dir)).await
+    }
+    async fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.clone(), move |vfs| Vfs::create_dir(&vfs, &dir)).await
+    }
+    async fn remove_dir(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.clone(), move |vfs| Vfs::remove_dir(&vfs, &dir)).await
+    }
+    async fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        let dir = dir.clone();
+        blocking(self.clone(), move |vfs| Vfs::remove_dir_all(&vfs, &dir)).await
+    }
+    async fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        let (from, to) = (from.clone(), to.clone());
+        blocking(self.clone(), move |vfs| Vfs::rename(&vfs, &from, &to)).await
+    }
+    async fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        let file = file.clone();
+        blocking(self.clone(), move |vfs| Vfs::remove_file(&vfs, &file)).await
+    }
+    async fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        let (from, to) = (from.clone(), to.clone());
+        blocking(self.clone(), move |vfs| Vfs::copy(&vfs, &from, &to)).await
+    }
+    async fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata> {
+        let path = path.clone();
+        blocking(self.clone(), move |vfs| Vfs::metadata(&vfs, &path)).await
+    }
+}
+
+///Nothing a [MemoryVfs] does blocks, so its calls are made directly
+#[async_trait]
+impl AsyncVfs for MemoryVfs {
+    fn root(&self) -> &PathBuf {
+        Vfs::root(self)
+    }
+    fn lazy_dirs(&self) -> bool {
+        Vfs::lazy_dirs(self)
+    }
+    async fn read_domain_file(&self, domain: &str) -> Result<DomainOptions> {
+        Vfs::read_domain_file(self, domain)
+    }
+    async fn read(&self, file: VfsPath) -> Result<AsyncReader> {
+        Ok(Box::new(Cursor::new(Vfs::read_bytes(self, file)?)))
+    }
+    async fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        Vfs::read_bytes(self, file)
+    }
+    async fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn AsyncVfsFile>> {
+        Ok(Box::new(MemFile(Vfs::open_with(self, file, opts)?)))
+    }
+    async fn read_dir(&self, dir: &VfsPath) -> Result<AsyncReadDir> {
+        let entries: Vec<_> = Vfs::read_dir(self, dir)?.collect();
+        Ok(Box::pin(stream::iter(entries)))
+    }
+    async fn create_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        Vfs::create_dir_all(self, dir)
+    }
+    async fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        Vfs::create_dir(self, dir)
+    }
+    async fn remove_dir(&self, dir: &VfsPath) -> Result<()> {
+        Vfs::remove_dir(self, dir)
+    }
+    async fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        Vfs::remove_dir_all(self, dir)
+    }
+    async fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        Vfs::rename(self, from, to)
+    }
+    async fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        Vfs::remove_file(self, file)
+    }
+    async fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        Vfs::copy(self, from, to)
+    }
+    async fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata> {
+        Vfs::metadata(self, path)
+    }
+}
+
+///A file opened by the [AsyncVfs] of [MemoryVfs], every operation completes immediately
+struct MemFile(Box<dyn VfsFile>);
+
+#[async_trait]
+impl AsyncVfsFile for MemFile {
+    fn path(&self) -> VfsPath {
+        self.0.path()
+    }
+    async fn sync_all(&mut self) -> Result<()> {
+        self.0.sync_all()
+    }
+}
+
+impl AsyncRead for MemFile {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let n = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MemFile {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(self.0.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for MemFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        self.0.seek(position).map(|_| ())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(self.0.stream_position())
+    }
+}
@@ -0,0 +1,146 @@
+//! Crash safe replacement of files in a [Vfs].
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::hash::ContentHash;
+use crate::vfs::{is_not_found, unique_name, Result, Vfs, VfsErr, VfsFile, VfsOpenOptions, VfsPath};
+
+///Starts the name of every [AtomicWriter] temp file and of the staging directory of a publish
+pub(crate) const ATOMIC_TMP_PREFIX: &str = ".~";
+
+///Serialises the check and rename of commits which expect a previous hash so two writers in this process
+///can't both pass the check before either has renamed. It doesn't exclude other processes, see [AtomicWriter].
+static COMPARE_AND_SWAP: Mutex<()> = Mutex::new(());
+
+///Writes a new version of a file without readers ever seeing it half written.
+///Everything written goes to a hidden temp file in the target's directory, so it's on the same filesystem as the target.
+///[AtomicWriter::commit] fsyncs it, renames it over the target and then fsyncs the target's directory so the rename
+///survives a crash. It never falls back to copying, a rename which isn't possible fails the commit and leaves the
+///target untouched. If the writer is dropped without committing, the temp file is deleted.
+///
+///The compare-and-swap of [AtomicWriter::expect_previous] is only atomic between writers in the same process.
+///Processes sharing a root must serialise their writes to a file some other way, e.g. with one deploying process.
+pub struct AtomicWriter<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    vfs: &'a F,
+    target: VfsPath,
+    tmp: VfsPath,
+    file: Option<Box<dyn VfsFile>>,
+    ///`Some(None)` means the target must not exist when committing
+    expected: Option<Option<ContentHash>>,
+}
+
+impl<'a, F> AtomicWriter<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    pub(crate) fn new(vfs: &'a F, target: VfsPath) -> Result<Self> {
+        //a sibling of the target so the rename can't cross filesystems
+        let name = target.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let tmp = target
+            .parent_unchecked()
+            .ok_or_else(|| VfsErr::PathEscapesRoot(target.as_path().to_string_lossy().to_string()))?
+            .join(format!("{}{}.{}", ATOMIC_TMP_PREFIX, name, unique_name("atomic")))?;
+        let mut opts = VfsOpenOptions::new();
+        opts.write(true).create_new(true);
+        let file = vfs.open_with(tmp.clone(), opts)?;
+        Ok(AtomicWriter {
+            vfs,
+            target,
+            tmp,
+            file: Some(file),
+            expected: None,
+        })
+    }
+
+    pub fn target(&self) -> &VfsPath {
+        &self.target
+    }
+
+    ///Makes [AtomicWriter::commit] fail with [VfsErr::Conflict] unless the target's content still hashes to `previous`.
+    ///`None` means the target must not exist. This stops two deploys from silently overwriting each other.
+    pub fn expect_previous(mut self, previous: Option<ContentHash>) -> Self {
+        self.expected = Some(previous);
+        self
+    }
+
+    ///Durably replaces the target with everything written so far
+    pub fn commit(mut self) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.sync_all()?;
+        }
+        {
+            let _guard = self
+                .expected
+                .map(|_| COMPARE_AND_SWAP.lock().unwrap_or_else(|e| e.into_inner()));
+            if let Some(expected) = &self.expected {
+                let current = current_hash(self.vfs, &self.target)?;
+                if &current != expected {
+                    return Err(VfsErr::Conflict(self.target.as_path().to_string_lossy().to_string()));
+                }
+            }
+            //Vfs::rename never degrades to a copy which would rewrite the target in place
+            self.vfs.rename(&self.tmp, &self.target)?;
+        }
+        if let Some(parent) = self.target.parent_unchecked() {
+            self.vfs.sync_dir(&parent)?;
+        }
+        Ok(())
+    }
+}
+
+///True if `path` is the temp file of an [AtomicWriter] which hasn't been committed or a publish's staging directory,
+///directory listings skip these
+pub(crate) fn is_atomic_tmp(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(ATOMIC_TMP_PREFIX))
+}
+
+///The hash of `path`'s content or [None] if it doesn't exist
+fn current_hash<F: Vfs + ?Sized>(vfs: &F, path: &VfsPath) -> Result<Option<ContentHash>> {
+    match vfs.read(path.clone()) {
+        Ok(mut input) => Ok(Some(ContentHash::of_reader(&mut input).map_err(VfsErr::Io)?.0)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl<'a, F> Write for AtomicWriter<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => Err(std::io::Error::other("AtomicWriter already committed")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, F> Drop for AtomicWriter<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    fn drop(&mut self) {
+        //close the file first, there's nothing to flush for an abandoned write
+        self.file.take();
+        match self.vfs.remove_file(&self.tmp) {
+            Ok(()) => {}
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => warn!("Failed to remove temp file {} - {}", self.tmp.as_path().to_string_lossy(), e),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Symlink safe path resolution used by [FilesystemVfs](crate::FilesystemVfs) in hardened mode.
+//! Every path is opened relative to a handle on the root directory so that neither `..` nor a symlink
+//! planted by a tenant can take a lookup outside of the root.
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use rustix::fs::{
+    fstat, mkdirat, openat, openat2, renameat, unlinkat, AtFlags, Dir, FileType, Mode, OFlags, ResolveFlags, CWD,
+};
+use rustix::io::Errno;
+
+use crate::vfs::{Result, VfsErr};
+
+///Opens `rel` (which must be relative to `root`) without following any symlink.
+///On Linux 5.6+ this is a single `openat2(RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS)` call,
+///older kernels fall back to walking one component at a time with `O_NOFOLLOW`.
+pub(crate) fn open(root: &Path, rel: &Path, flags: OFlags) -> Result<File> {
+    let root_fd = open_root(root)?;
+    let flags = flags | OFlags::CLOEXEC;
+    //openat2 rejects a mode unless the file may be created
+    let mode = if flags.contains(OFlags::CREATE) {
+        Mode::from_raw_mode(0o666)
+    } else {
+        Mode::empty()
+    };
+    if rel.as_os_str().is_empty() {
+        return openat(&root_fd, ".", flags, mode).map(File::from).map_err(|e| map_errno(e, rel));
+    }
+    let resolve = ResolveFlags::BENEATH | ResolveFlags::NO_SYMLINKS | ResolveFlags::NO_MAGICLINKS;
+    match openat2(&root_fd, rel, flags, mode, resolve) {
+        Ok(fd) => return Ok(File::from(fd)),
+        //openat2 was added in 5.6, anything older gets the component walk below
+        Err(Errno::NOSYS) => {}
+        Err(e) => return Err(map_errno(e, rel)),
+    }
+    walk(root_fd, rel, flags, mode).map(File::from)
+}
+
+///Lists the entries of the directory `rel` under `root`, returning their full paths.
+pub(crate) fn read_dir(root: &Path, rel: &Path) -> Result<Vec<PathBuf>> {
+    let dir = open(root, rel, OFlags::RDONLY | OFlags::DIRECTORY)?;
+    let base = root.join(rel);
+    let mut paths = vec![];
+    for entry in Dir::read_from(&dir).map_err(|e| VfsErr::Io(e.into()))? {
+        let entry = entry.map_err(|e| VfsErr::Io(e.into()))?;
+        let name = OsStr::from_bytes(entry.file_name().to_bytes());
+        if name == "." || name == ".." {
+            continue;
+        }
+        paths.push(base.join(name));
+    }
+    Ok(paths)
+}
+
+fn open_root(root: &Path) -> Result<OwnedFd> {
+    openat(CWD, root, OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())
+        .map_err(|e| VfsErr::Io(e.into()))
+}
+
+///Creates the directory `rel` and any missing parents under `root` without traversing symlinks.
+pub(crate) fn create_dir_all(root: &Path, rel: &Path) -> Result<()> {
+    let mut dir = open_root(root)?;
+    for name in names(rel)? {
+        match mkdirat(&dir, name, Mode::from_raw_mode(0o777)) {
+            Ok(()) | Err(Errno::EXIST) => {}
+            Err(e) => return Err(map_errno(e, rel)),
+        }
+        dir = open_child_dir(&dir, name, rel)?;
+    }
+    Ok(())
+}
+
+///Creates the directory `rel` under `root`, whose parent must already exist, without traversing symlinks.
+pub(crate) fn create_dir(root: &Path, rel: &Path) -> Result<()> {
+    let (dir, name) = open_parent(root, rel)?;
+    mkdirat(&dir, name, Mode::from_raw_mode(0o777)).map_err(|e| map_errno(e, rel))
+}
+
+///Removes the file, or empty directory if `is_dir`, at `rel` under `root` without traversing symlinks.
+///A symlink itself can be removed, it is never followed.
+pub(crate) fn remove(root: &Path, rel: &Path, is_dir: bool) -> Result<()> {
+    let (dir, name) = open_parent(root, rel)?;
+    let flags = if is_dir { AtFlags::REMOVEDIR } else { AtFlags::empty() };
+    unlinkat(&dir, name, flags).map_err(|e| map_errno(e, rel))
+}
+
+///Removes the directory `rel` under `root` and everything in it. Symlinks inside it are removed, never followed.
+pub(crate) fn remove_dir_all(root: &Path, rel: &Path) -> Result<()> {
+    let dir = open(root, rel, OFlags::RDONLY | OFlags::DIRECTORY)?;
+    empty_dir(OwnedFd::from(dir), rel)?;
+    remove(root, rel, true)
+}
+
+fn empty_dir(dir: OwnedFd, rel: &Path) -> Result<()> {
+    let mut names = vec![];
+    for entry in Dir::read_from(&dir).map_err(|e| VfsErr::Io(e.into()))? {
+        let entry = entry.map_err(|e| VfsErr::Io(e.into()))?;
+        let name = entry.file_name().to_owned();
+        if name.as_bytes() != b"." && name.as_bytes() != b".." {
+            names.push(name);
+        }
+    }
+    for name in names {
+        match unlinkat(&dir, name.as_c_str(), AtFlags::empty()) {
+            Ok(()) => {}
+            //unlink refuses directories, empty it first
+            Err(Errno::ISDIR) | Err(Errno::PERM) => {
+                let child = open_child_dir(&dir, OsStr::from_bytes(name.as_bytes()), rel)?;
+                empty_dir(child, rel)?;
+                unlinkat(&dir, name.as_c_str(), AtFlags::REMOVEDIR).map_err(|e| map_errno(e, rel))?;
+            }
+            Err(e) => return Err(map_errno(e, rel)),
+        }
+    }
+    Ok(())
+}
+
+///Stats `rel` under `root` without following any symlink to reach it
+pub(crate) fn metadata(root: &Path, rel: &Path) -> Result<std::fs::Metadata> {
+    open(root, rel, OFlags::PATH)?.metadata().map_err(VfsErr::Io)
+}
+
+///Renames `from` to `to`, both relative to `root`, without traversing symlinks to reach either.
+pub(crate) fn rename(root: &Path, from: &Path, to: &Path) -> Result<()> {
+    let (from_dir, from_name) = open_parent(root, from)?;
+    let (to_dir, to_name) = open_parent(root, to)?;
+    renameat(&from_dir, from_name, &to_dir, to_name).map_err(|e| map_errno(e, from))
+}
+
+fn open_parent<'a>(root: &Path, rel: &'a Path) -> Result<(File, &'a OsStr)> {
+    let name = match rel.file_name() {
+        Some(name) => name,
+        None => return Err(VfsErr::PathEscapesRoot(rel.to_string_lossy().to_string())),
+    };
+    let parent = rel.parent().unwrap_or(Path::new(""));
+    Ok((open(root, parent, OFlags::RDONLY | OFlags::DIRECTORY)?, name))
+}
+
+fn names(rel: &Path) -> Result<Vec<&OsStr>> {
+    rel.components()
+        .map(|c| match c {
+            Component::Normal(name) => Ok(name),
+            _ => Err(VfsErr::PathEscapesRoot(rel.to_string_lossy().to_string())),
+        })
+        .collect()
+}
+
+fn open_child_dir(dir: &OwnedFd, name: &OsStr, rel: &Path) -> Result<OwnedFd> {
+    //without O_PATH, O_NOFOLLOW makes the open fail with ELOOP if the component is a symlink
+    openat(
+        dir,
+        name,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .map_err(|e| map_errno(e, rel))
+}
+
+fn walk(root_fd: OwnedFd, rel: &Path, flags: OFlags, mode: Mode) -> Result<OwnedFd> {
+    let names = names(rel)?;
+    let mut dir = root_fd;
+    for (idx, name) in names.iter().enumerate() {
+        if idx + 1 == names.len() {
+            let fd = openat(&dir, *name, flags | OFlags::NOFOLLOW, mode).map_err(|e| map_errno(e, rel))?;
+            //O_PATH | O_NOFOLLOW opens the symlink itself rather than failing, refuse it like openat2 does
+            if flags.contains(OFlags::PATH) {
+                let stat = fstat(&fd).map_err(|e| VfsErr::Io(e.into()))?;
+                if FileType::from_raw_mode(stat.st_mode) == FileType::Symlink {
+                    return Err(map_errno(Errno::LOOP, rel));
+                }
+            }
+            return Ok(fd);
+        }
+        dir = open_child_dir(&dir, name, rel)?;
+    }
+    Ok(dir)
+}
+
+fn map_errno(e: Errno, rel: &Path) -> VfsErr {
+    match e {
+        //EXDEV is what RESOLVE_BENEATH reports for an escape, ELOOP is a symlink that we refused to follow
+        Errno::XDEV | Errno::LOOP => VfsErr::PathEscapesRoot(rel.to_string_lossy().to_string()),
+        e => VfsErr::Io(e.into()),
+    }
+}
@@ -0,0 +1,272 @@
+//! A [Vfs] wrapper which memoises domain lookups.
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::domain::{resolve_with, DomainEntry, DomainIndex, Fingerprint};
+use crate::vfs::{
+    is_not_found, DomainOptions, Result, Vfs, VfsErr, VfsFile, VfsMetadata, VfsOpenOptions, VfsPath, VirtualReadDir,
+    DOMAINS_SUBDIR,
+};
+use crate::watch::Watcher;
+
+///Once the cache holds this many domains, expired entries are dropped and, if that isn't enough, everything is
+const MAX_ENTRIES: usize = 65536;
+
+struct CachedDomain {
+    ///[None] for a domain which doesn't exist
+    options: Option<DomainOptions>,
+    expires: Instant,
+    ///Every domain file consulted to resolve the domain and its fingerprint, [None] if it didn't exist
+    files: Vec<(VfsPath, Option<Fingerprint>)>,
+}
+
+///Wraps a [Vfs] to memoise [Vfs::read_domain_file], everything else is passed straight through.
+///Entries expire after a TTL, a shorter one for domains which don't exist so that a flood of requests for unknown
+///hosts doesn't reach the backend, a cached miss is answered without touching the backend at all. When the backend
+///records modified times, a cached domain is also dropped as soon as any of the files it was resolved from changes.
+///Changes made through this [CachingVfs] clear the cache immediately, changes made some other way can be announced
+///with [CachingVfs::invalidate_domain].
+pub struct CachingVfs<F>
+    where
+        F: Vfs,
+{
+    inner: F,
+    ttl: Duration,
+    negative_ttl: Duration,
+    check_mtime: bool,
+    domains: RwLock<HashMap<String, CachedDomain>>,
+}
+
+impl<F> CachingVfs<F>
+    where
+        F: Vfs,
+{
+    ///Caches domains for a minute, unknown domains for 5 seconds and checks modified times on every hit of a known domain
+    pub fn new(inner: F) -> Self {
+        CachingVfs {
+            inner,
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+            check_mtime: true,
+            domains: RwLock::new(HashMap::new()),
+        }
+    }
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+    ///How long a domain which doesn't exist is remembered as not existing.
+    ///Misses are never checked against the backend, so a domain file created without going through this [CachingVfs],
+    ///e.g. through the inner [Vfs], a [BoundVfs](crate::vfs::BoundVfs) on it or another process, stays a miss for up to
+    ///`negative_ttl` unless it's announced with [CachingVfs::invalidate_domain].
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+    ///When false, cached domains are trusted until they expire without checking their files' modified times
+    pub fn with_mtime_checks(mut self, check_mtime: bool) -> Self {
+        self.check_mtime = check_mtime;
+        self
+    }
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+    ///Forgets `domain` so it's read from the backend next time.
+    ///This doesn't forget other domains which are aliases of `domain` or matched a wildcard, use [CachingVfs::invalidate_all] for that.
+    pub fn invalidate_domain(&self, domain: &str) {
+        self.domains.write().unwrap_or_else(|e| e.into_inner()).remove(domain);
+    }
+    pub fn invalidate_all(&self) {
+        self.domains.write().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    fn fingerprint(&self, path: &VfsPath) -> Result<Option<Fingerprint>> {
+        match self.inner.metadata(path) {
+            Ok(meta) => Ok(Some((meta.modified(), meta.len()))),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///True if the `files` a domain was resolved from look unchanged, or the backend can't tell
+    fn is_fresh(&self, files: &[(VfsPath, Option<Fingerprint>)]) -> bool {
+        files.iter().all(|(path, known)| match (known, self.fingerprint(path)) {
+            //without a modified time a rewrite of the same size can't be told apart, only the TTL applies
+            (Some((None, _)), Ok(Some(_))) => true,
+            (known, Ok(current)) => *known == current,
+            (_, Err(_)) => false,
+        })
+    }
+
+    fn cached(&self, domain: &str) -> Option<Result<DomainOptions>> {
+        let (options, files) = {
+            let domains = self.domains.read().unwrap_or_else(|e| e.into_inner());
+            let cached = domains.get(domain)?;
+            if cached.expires <= Instant::now() {
+                return None;
+            }
+            match &cached.options {
+                //misses aren't checked against the backend, they only last for the short negative TTL
+                None => return Some(Err(VfsErr::Domain(domain.to_string()))),
+                Some(options) if !self.check_mtime => return Some(Ok(options.clone())),
+                Some(options) => (options.clone(), cached.files.clone()),
+            }
+        };
+        //checked without the lock so a slow backend doesn't hold up lookups which miss
+        if !self.is_fresh(&files) {
+            return None;
+        }
+        Some(Ok(options))
+    }
+
+    fn store(&self, domain: &str, cached: CachedDomain) {
+        let mut domains = self.domains.write().unwrap_or_else(|e| e.into_inner());
+        if domains.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            domains.retain(|_, cached| cached.expires > now);
+            if domains.len() >= MAX_ENTRIES {
+                domains.clear();
+            }
+        }
+        domains.insert(domain.to_string(), cached);
+    }
+
+    ///Drops every cached domain if `path` is a domain file, or a directory which may contain them
+    fn invalidate_path(&self, path: &Path) {
+        let domains_dir = self.inner.root().join(DOMAINS_SUBDIR);
+        if path.starts_with(&domains_dir) || domains_dir.starts_with(path) {
+            self.invalidate_all();
+        }
+    }
+}
+
+impl<F> Vfs for CachingVfs<F>
+    where
+        F: Vfs,
+{
+    fn root(&self) -> &PathBuf {
+        self.inner.root()
+    }
+    fn resolve(&self, child: &str) -> Result<VfsPath> {
+        self.inner.resolve(child)
+    }
+    fn check_path(&self, path: &Path) -> Result<()> {
+        self.inner.check_path(path)
+    }
+    fn lazy_dirs(&self) -> bool {
+        self.inner.lazy_dirs()
+    }
+    fn create_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+    fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.inner.create_dir(dir)
+    }
+    fn remove_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.inner.remove_dir(dir)?;
+        self.invalidate_path(dir.as_path());
+        Ok(())
+    }
+    fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        let res = self.inner.remove_dir_all(dir);
+        //even a failed removal may have removed some files
+        self.invalidate_path(dir.as_path());
+        res
+    }
+    fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        self.inner.rename(from, to)?;
+        self.invalidate_path(from.as_path());
+        self.invalidate_path(to.as_path());
+        Ok(())
+    }
+    fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        self.inner.remove_file(file)?;
+        self.invalidate_path(file.as_path());
+        Ok(())
+    }
+    fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        let copied = self.inner.copy(from, to)?;
+        self.invalidate_path(to.as_path());
+        Ok(copied)
+    }
+    fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata> {
+        self.inner.metadata(path)
+    }
+    fn sync_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.inner.sync_dir(dir)
+    }
+    fn read(&self, file: VfsPath) -> Result<Box<dyn Read + '_>> {
+        self.inner.read(file)
+    }
+    fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        self.inner.read_bytes(file)
+    }
+    fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn VfsFile>> {
+        if opts.is_write() || opts.is_append() {
+            //the file is written after it's opened, so this only helps when it's done quicker than a lookup
+            //which is why the backend's modified times are checked too
+            self.invalidate_path(file.as_path());
+        }
+        self.inner.open_with(file, opts)
+    }
+    fn read_dir(&self, dir: &VfsPath) -> Result<VirtualReadDir> {
+        self.inner.read_dir(dir)
+    }
+    fn domain_index(&self) -> Option<&DomainIndex> {
+        self.inner.domain_index()
+    }
+    fn watch(&self, service_id: i64, is_draft: bool, version: &str) -> Result<Watcher> {
+        self.inner.watch(service_id, is_draft, version)
+    }
+
+    fn read_domain_file(&self, domain: &str) -> Result<DomainOptions> {
+        if let Some(res) = self.cached(domain) {
+            return res;
+        }
+        let mut files = vec![];
+        let res = resolve_with(domain, |candidate| {
+            let path = self.domain_file(candidate)?;
+            let fingerprint = self.fingerprint(&path)?;
+            files.push((path, fingerprint));
+            if fingerprint.is_none() {
+                return Ok(None);
+            }
+            match self.read_domain_entry(candidate) {
+                Ok(entry) => Ok(Some(entry)),
+                //removed between the two calls
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(e),
+            }
+        });
+        let now = Instant::now();
+        match &res {
+            Ok(options) => self.store(
+                domain,
+                CachedDomain {
+                    options: Some(options.clone()),
+                    expires: now + self.ttl,
+                    files,
+                },
+            ),
+            Err(VfsErr::Domain(_)) => self.store(
+                domain,
+                CachedDomain {
+                    options: None,
+                    expires: now + self.negative_ttl,
+                    files,
+                },
+            ),
+            //other errors, e.g. a loop or unreadable file, aren't cached
+            Err(_) => {}
+        }
+        res
+    }
+    fn read_domain_entry(&self, domain: &str) -> Result<DomainEntry> {
+        self.inner.read_domain_entry(domain)
+    }
+}
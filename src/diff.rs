@@ -0,0 +1,156 @@
+//! Comparing the files of two drafts or versions of a service.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::Path;
+
+use similar::TextDiff;
+
+use crate::ecma::ECMA_MANIFEST_FILE;
+use crate::hash::ContentHash;
+use crate::version::{dir_exists, manifest_key, validate_version_name, MANIFEST_FILE};
+use crate::vfs::{DirEntry, Result, Vfs, VfsErr, VfsPath, ECMA_SUBDIR};
+
+///Identifies a draft or a published version of a service
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VersionRef {
+    pub name: String,
+    pub is_draft: bool,
+}
+
+impl VersionRef {
+    pub fn draft(name: &str) -> Self {
+        VersionRef {
+            name: name.to_string(),
+            is_draft: true,
+        }
+    }
+    pub fn version(name: &str) -> Self {
+        VersionRef {
+            name: name.to_string(),
+            is_draft: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+///A file which differs between the two sides of a [VersionDiff]
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    ///The `/` separated path of the file relative to the version directory
+    pub path: String,
+    pub kind: ChangeKind,
+    ///The hash of the file in the old version, [None] if it was added
+    pub old: Option<ContentHash>,
+    ///The hash of the file in the new version, [None] if it was removed
+    pub new: Option<ContentHash>,
+    ///A unified diff for `.xml` files and `.js` files under [ECMA_SUBDIR], [None] for any other file
+    pub diff: Option<String>,
+}
+
+///Every file which was added, removed or modified between two versions, sorted by path.
+///Files whose content hashes are equal are unchanged and not included.
+#[derive(Debug, Clone, Default)]
+pub struct VersionDiff {
+    pub changes: Vec<FileChange>,
+}
+
+impl VersionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+    pub fn added(&self) -> impl Iterator<Item=&FileChange> {
+        self.of_kind(ChangeKind::Added)
+    }
+    pub fn removed(&self) -> impl Iterator<Item=&FileChange> {
+        self.of_kind(ChangeKind::Removed)
+    }
+    pub fn modified(&self) -> impl Iterator<Item=&FileChange> {
+        self.of_kind(ChangeKind::Modified)
+    }
+    fn of_kind(&self, kind: ChangeKind) -> impl Iterator<Item=&FileChange> {
+        self.changes.iter().filter(move |change| change.kind == kind)
+    }
+}
+
+///See [Vfs::diff_versions]
+pub(crate) fn diff_versions<F: Vfs + ?Sized>(vfs: &F, service_id: i64, a: &VersionRef, b: &VersionRef) -> Result<VersionDiff> {
+    let old = files(vfs, service_id, a)?;
+    let new = files(vfs, service_id, b)?;
+    let paths: BTreeSet<_> = old.keys().chain(new.keys()).cloned().collect();
+    let mut diff = VersionDiff::default();
+    for path in paths {
+        let (kind, old_file, new_file) = match (old.get(&path), new.get(&path)) {
+            (Some(old_file), Some(new_file)) => (ChangeKind::Modified, Some(old_file), Some(new_file)),
+            (None, Some(new_file)) => (ChangeKind::Added, None, Some(new_file)),
+            (Some(old_file), None) => (ChangeKind::Removed, Some(old_file), None),
+            (None, None) => continue,
+        };
+        let text = is_text(&path);
+        let old_file = old_file.map(|f| content(vfs, f, text)).transpose()?;
+        let new_file = new_file.map(|f| content(vfs, f, text)).transpose()?;
+        let old_hash = old_file.as_ref().map(|(hash, _)| *hash);
+        let new_hash = new_file.as_ref().map(|(hash, _)| *hash);
+        if old_hash == new_hash {
+            continue;
+        }
+        let diff_text = if text {
+            let old_text = old_file.and_then(|(_, text)| text).unwrap_or_default();
+            let new_text = new_file.and_then(|(_, text)| text).unwrap_or_default();
+            Some(
+                TextDiff::from_lines(&old_text, &new_text)
+                    .unified_diff()
+                    .header(&format!("{}/{}", a.name, path), &format!("{}/{}", b.name, path))
+                    .to_string(),
+            )
+        } else {
+            None
+        };
+        diff.changes.push(FileChange {
+            path,
+            kind,
+            old: old_hash,
+            new: new_hash,
+            diff: diff_text,
+        });
+    }
+    Ok(diff)
+}
+
+///Every file in the version keyed by its `/` separated relative path, except the generated [MANIFEST_FILE] and [ECMA_MANIFEST_FILE]
+fn files<F: Vfs + ?Sized>(vfs: &F, service_id: i64, version: &VersionRef) -> Result<BTreeMap<String, VfsPath>> {
+    validate_version_name(&version.name)?;
+    let dir = vfs.version_dir(service_id, version.is_draft, &version.name)?;
+    if !dir_exists(vfs, &dir)? {
+        return Err(VfsErr::VersionNotFound(version.name.clone()));
+    }
+    let mut files = BTreeMap::new();
+    for entry in vfs.dir_stream(dir)? {
+        let DirEntry { rel, path, .. } = entry?;
+        if rel == Path::new(MANIFEST_FILE) || rel == Path::new(ECMA_MANIFEST_FILE) {
+            continue;
+        }
+        files.insert(manifest_key(&rel), path);
+    }
+    Ok(files)
+}
+
+fn is_text(path: &str) -> bool {
+    path.ends_with(".xml") || (path.starts_with(&format!("{}/", ECMA_SUBDIR)) && path.ends_with(".js"))
+}
+
+///The hash of `path` and, if `text`, its content
+fn content<F: Vfs + ?Sized>(vfs: &F, path: &VfsPath, text: bool) -> Result<(ContentHash, Option<String>)> {
+    let mut input = vfs.read(path.clone())?;
+    if !text {
+        return Ok((ContentHash::of_reader(&mut input).map_err(VfsErr::Io)?.0, None));
+    }
+    let mut data = vec![];
+    input.read_to_end(&mut data).map_err(VfsErr::Io)?;
+    Ok((ContentHash::of(&data), Some(String::from_utf8_lossy(&data).to_string())))
+}
@@ -0,0 +1,490 @@
+//! Managing the domain files which route a domain to a version of a service.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::atomic::is_atomic_tmp;
+use crate::hash::ContentHash;
+use crate::version::{dir_exists, validate_version_name};
+use crate::vfs::{is_not_found, DomainOptions, Result, Vfs, VfsErr, VfsPath, DOMAINS_SUBDIR};
+
+///The most alias hops [Vfs::read_domain_file] follows before giving up
+const MAX_ALIAS_HOPS: usize = 32;
+
+///What a domain file contains, either the options of the domain or the name of another domain to use the options of
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DomainEntry {
+    Alias { alias_of: String },
+    Options(DomainOptions),
+}
+
+///The format written by this version of the crate. Files without a `format` field, JSON or the line based
+///format which came before it, are format 0 and are still read, see [parse_domain_file].
+pub const DOMAIN_FILE_FORMAT: u32 = 1;
+
+///A domain file as stored in `domains/`, i.e. a [DomainEntry] and the format it was written in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainFile {
+    #[serde(default)]
+    pub format: u32,
+    #[serde(flatten)]
+    pub entry: DomainEntry,
+}
+
+impl DomainFile {
+    ///`entry` in the current [DOMAIN_FILE_FORMAT]
+    pub fn new(entry: DomainEntry) -> Self {
+        DomainFile {
+            format: DOMAIN_FILE_FORMAT,
+            entry,
+        }
+    }
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self).map_err(VfsErr::JsonErr)
+    }
+}
+
+///Just the `format` of a JSON domain file, read before the rest which may not parse in a newer format
+#[derive(Deserialize)]
+struct FormatOnly {
+    #[serde(default)]
+    format: u32,
+}
+
+///Parses a domain file in any format. JSON is recognised by its leading `{`, anything else is taken to be the legacy
+///line based format: the service ID on the first line, the version on the second and an optional third line which
+///is `draft` (or `true`) for a draft.
+///A file in a format newer than [DOMAIN_FILE_FORMAT] fails with [VfsErr::InvalidDomainFile] rather than being misread.
+pub fn parse_domain_file(data: &[u8]) -> Result<DomainFile> {
+    let text = String::from_utf8_lossy(data);
+    if text.trim_start().starts_with('{') {
+        let FormatOnly { format } = serde_json::from_slice(data).map_err(VfsErr::JsonErr)?;
+        if format > DOMAIN_FILE_FORMAT {
+            return Err(VfsErr::InvalidDomainFile(format!("unsupported format {}", format)));
+        }
+        return serde_json::from_slice(data).map_err(VfsErr::JsonErr);
+    }
+    let invalid = |reason: &str| VfsErr::InvalidDomainFile(format!("{} in legacy domain file", reason));
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    let service_id = lines
+        .next()
+        .ok_or_else(|| invalid("missing service ID"))?
+        .parse::<i64>()
+        .map_err(|_| invalid("invalid service ID"))?;
+    let version = lines.next().ok_or_else(|| invalid("missing version"))?;
+    let is_draft = match lines.next() {
+        None | Some("false") | Some("version") => false,
+        Some("true") | Some("draft") => true,
+        Some(_) => return Err(invalid("invalid draft flag")),
+    };
+    if lines.next().is_some() {
+        return Err(invalid("unexpected extra lines"));
+    }
+    Ok(DomainFile {
+        format: 0,
+        entry: DomainEntry::Options(DomainOptions::new(service_id, version, is_draft)),
+    })
+}
+
+///See [Vfs::migrate_domain_files]
+pub(crate) fn migrate_domain_files<F: Vfs + ?Sized>(vfs: &F) -> Result<Vec<String>> {
+    let dir = vfs.resolve(DOMAINS_SUBDIR)?;
+    let files: Vec<VfsPath> = match vfs.read_dir(&dir) {
+        Ok(entries) => entries
+            .filter(|path| path.as_path().parent() == Some(dir.as_path()) && !is_atomic_tmp(path.as_path()))
+            .collect(),
+        Err(e) if is_not_found(&e) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut migrated = vec![];
+    for path in files {
+        let domain = match path.file_name().and_then(|name| name.to_str()) {
+            Some(domain) => domain.to_string(),
+            None => continue,
+        };
+        let data = vfs.read_bytes(path.clone())?;
+        let file = match parse_domain_file(&data) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Not migrating domain file {} - {}", path.as_path().to_string_lossy(), e);
+                continue;
+            }
+        };
+        if file.format >= DOMAIN_FILE_FORMAT {
+            continue;
+        }
+        let mut writer = vfs.atomic_writer(path)?.expect_previous(Some(ContentHash::of(&data)));
+        writer.write_all(&DomainFile::new(file.entry.clone()).to_json()?).map_err(VfsErr::Io)?;
+        writer.commit()?;
+        index_update(vfs, &domain, Some(file.entry));
+        migrated.push(domain);
+    }
+    migrated.sort();
+    Ok(migrated)
+}
+
+///Checks `domain` is a valid hostname: at most 253 characters of dot separated labels,
+///each 1 to 63 ASCII letters, digits or hyphens and not starting or ending with a hyphen.
+///The first label may also be `*` to match any sub-domain, see [Vfs::read_domain_file].
+pub fn validate_domain(domain: &str) -> Result<()> {
+    let invalid = || VfsErr::InvalidDomain(domain.to_string());
+    if domain.is_empty() || domain.len() > 253 {
+        return Err(invalid());
+    }
+    let labels = domain.strip_prefix("*.").unwrap_or(domain);
+    for label in labels.split('.') {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if !valid {
+            return Err(invalid());
+        }
+    }
+    Ok(())
+}
+
+///The domain files which can serve `domain`, most specific first.
+///For `a.shop.example.com` that's the file for the domain itself, then `*.shop.example.com`, `*.example.com` and `*.com`.
+pub fn domain_candidates(domain: &str) -> Vec<String> {
+    let mut candidates = vec![domain.to_string()];
+    let mut rest = domain.strip_prefix("*.").unwrap_or(domain);
+    while let Some((_, parent)) = rest.split_once('.') {
+        candidates.push(format!("*.{}", parent));
+        rest = parent;
+    }
+    candidates
+}
+
+///Follows `domain` through wildcards and aliases to its options, using `lookup` to find the entry of a domain file
+pub(crate) fn resolve_with<L>(domain: &str, mut lookup: L) -> Result<DomainOptions>
+    where
+        L: FnMut(&str) -> Result<Option<DomainEntry>>,
+{
+    let mut chain: Vec<String> = vec![];
+    let mut current = domain.to_string();
+    loop {
+        if chain.contains(&current) || chain.len() >= MAX_ALIAS_HOPS {
+            chain.push(current);
+            return Err(VfsErr::DomainLoop(chain.join(" -> ")));
+        }
+        let mut found = None;
+        for candidate in domain_candidates(&current) {
+            if let Some(entry) = lookup(&candidate)? {
+                found = Some(entry);
+                break;
+            }
+        }
+        chain.push(current);
+        match found {
+            Some(DomainEntry::Options(options)) => return Ok(options),
+            Some(DomainEntry::Alias { alias_of }) => current = alias_of,
+            None => return Err(VfsErr::Domain(chain.join(" -> "))),
+        }
+    }
+}
+
+///See [Vfs::read_domain_file]
+pub(crate) fn resolve_domain<F: Vfs + ?Sized>(vfs: &F, domain: &str) -> Result<DomainOptions> {
+    resolve_with(domain, |candidate| match vfs.read_domain_entry(candidate) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    })
+}
+
+///Fails with [VfsErr::VersionNotFound] unless the version `options` points at exists
+fn check_target<F: Vfs + ?Sized>(vfs: &F, options: &DomainOptions) -> Result<()> {
+    validate_version_name(&options.version)?;
+    let dir = vfs.version_dir(options.service_id, options.is_draft, &options.version)?;
+    if !dir_exists(vfs, &dir)? {
+        return Err(VfsErr::VersionNotFound(dir.as_path().to_string_lossy().to_string()));
+    }
+    Ok(())
+}
+
+///See [Vfs::write_domain_file]
+pub(crate) fn write_domain_file<F: Vfs + ?Sized>(vfs: &F, domain: &str, options: &DomainOptions) -> Result<()> {
+    validate_domain(domain)?;
+    check_target(vfs, options)?;
+    if !vfs.lazy_dirs() {
+        vfs.create_dir_all(&vfs.resolve(DOMAINS_SUBDIR)?)?;
+    }
+    let data = DomainFile::new(DomainEntry::Options(options.clone())).to_json()?;
+    vfs.write_atomic(vfs.domain_file(domain)?, &data)?;
+    index_update(vfs, domain, Some(DomainEntry::Options(options.clone())));
+    Ok(())
+}
+
+///See [Vfs::write_domain_alias]
+pub(crate) fn write_domain_alias<F: Vfs + ?Sized>(vfs: &F, domain: &str, alias_of: &str) -> Result<()> {
+    validate_domain(domain)?;
+    validate_domain(alias_of)?;
+    //refuse to write an alias which can't be resolved, including one which would close a loop
+    resolve_with(alias_of, |candidate| {
+        if candidate == domain {
+            return Ok(Some(DomainEntry::Alias { alias_of: alias_of.to_string() }));
+        }
+        match vfs.read_domain_entry(candidate) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    })?;
+    if !vfs.lazy_dirs() {
+        vfs.create_dir_all(&vfs.resolve(DOMAINS_SUBDIR)?)?;
+    }
+    let entry = DomainEntry::Alias { alias_of: alias_of.to_string() };
+    let data = DomainFile::new(entry.clone()).to_json()?;
+    vfs.write_atomic(vfs.domain_file(domain)?, &data)?;
+    index_update(vfs, domain, Some(entry));
+    Ok(())
+}
+
+///See [Vfs::delete_domain_file]
+pub(crate) fn delete_domain_file<F: Vfs + ?Sized>(vfs: &F, domain: &str) -> Result<()> {
+    validate_domain(domain)?;
+    let file = vfs.domain_file(domain)?;
+    match vfs.remove_file(&file) {
+        Ok(()) => {
+            index_update(vfs, domain, None);
+            Ok(())
+        }
+        Err(e) if is_not_found(&e) => Err(VfsErr::Domain(domain.to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+///See [Vfs::rebind_domain]
+pub(crate) fn rebind_domain<F: Vfs + ?Sized>(vfs: &F, domain: &str, version: &str, is_draft: bool) -> Result<DomainOptions> {
+    validate_domain(domain)?;
+    let file = vfs.domain_file(domain)?;
+    //the file is read once so the options rewritten are the ones the compare-and-swap guards
+    let data = match vfs.read_bytes(file.clone()) {
+        Ok(data) => data,
+        Err(e) if is_not_found(&e) => return Err(VfsErr::Domain(domain.to_string())),
+        Err(e) => return Err(e),
+    };
+    let previous = ContentHash::of(&data);
+    //everything but the version is kept
+    let mut options = match parse_domain_file(&data)?.entry {
+        DomainEntry::Options(options) => options,
+        DomainEntry::Alias { .. } => return Err(VfsErr::DomainIsAlias(domain.to_string())),
+    };
+    options.version = version.to_string();
+    options.is_draft = is_draft;
+    check_target(vfs, &options)?;
+    let data = DomainFile::new(DomainEntry::Options(options.clone())).to_json()?;
+    //fails with a conflict rather than undoing a rebind which happened since the file was read
+    let mut writer = vfs.atomic_writer(file)?.expect_previous(Some(previous));
+    writer.write_all(&data).map_err(VfsErr::Io)?;
+    writer.commit()?;
+    index_update(vfs, domain, Some(DomainEntry::Options(options.clone())));
+    Ok(options)
+}
+
+///Identifies the content of a domain file without reading it
+pub(crate) type Fingerprint = (Option<SystemTime>, u64);
+
+///Files modified this close to a refresh are read again by the next one. Filesystem timestamps are coarse,
+///so a file rewritten with the same size shortly after being indexed can keep the same modified time.
+const RACY_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct IndexState {
+    ///[None] for entries changed through this process or too recently to trust, forcing them to be read again by the next refresh
+    entries: HashMap<String, (Option<Fingerprint>, DomainEntry)>,
+    ///Set when entries change, the resolved maps below are rebuilt before they're next used
+    stale: bool,
+    resolved: HashMap<String, DomainOptions>,
+    by_service: BTreeMap<i64, BTreeSet<String>>,
+    by_version: BTreeMap<(i64, String, bool), BTreeSet<String>>,
+}
+
+impl IndexState {
+    fn insert(&mut self, domain: &str, fingerprint: Option<Fingerprint>, entry: DomainEntry) {
+        self.entries.insert(domain.to_string(), (fingerprint, entry));
+        self.stale = true;
+    }
+    fn remove(&mut self, domain: &str) {
+        if self.entries.remove(domain).is_some() {
+            self.stale = true;
+        }
+    }
+    ///Resolves every entry, an alias changes whenever the domain it points at does so they're all redone together
+    fn rebuild(&mut self) {
+        self.resolved.clear();
+        self.by_service.clear();
+        self.by_version.clear();
+        for domain in self.entries.keys() {
+            let options = resolve_with(domain, |candidate| Ok(self.entries.get(candidate).map(|(_, entry)| entry.clone())));
+            match options {
+                Ok(options) => {
+                    self.by_service.entry(options.service_id).or_default().insert(domain.clone());
+                    self.by_version
+                        .entry((options.service_id, options.version.clone(), options.is_draft))
+                        .or_default()
+                        .insert(domain.clone());
+                    self.resolved.insert(domain.clone(), options);
+                }
+                Err(e) => warn!("Skipping domain {} - {}", domain, e),
+            }
+        }
+        self.stale = false;
+    }
+}
+
+///A reverse index of the `domains/` directory, from services and their versions to the domains serving them.
+///Aliases and wildcard domains are indexed under the service they resolve to.
+///[DomainIndex::refresh] only reads the domain files whose modified time or size changed since the last refresh,
+///files changed through [Vfs::write_domain_file] and friends or modified in the second before a refresh are always read again.
+#[derive(Default)]
+pub struct DomainIndex {
+    state: RwLock<IndexState>,
+}
+
+impl DomainIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Brings the index up to date with the domain files in `vfs`.
+    ///The files are read without holding the lock, lookups only wait while the changes found are applied.
+    pub fn refresh<F: Vfs + ?Sized>(&self, vfs: &F) -> Result<()> {
+        let started = SystemTime::now();
+        let dir = vfs.resolve(DOMAINS_SUBDIR)?;
+        let files: Vec<VfsPath> = match vfs.read_dir(&dir) {
+            Ok(entries) => entries
+                .filter(|path| path.as_path().parent() == Some(dir.as_path()) && !is_atomic_tmp(path.as_path()))
+                .collect(),
+            Err(e) if is_not_found(&e) => vec![],
+            Err(e) => return Err(e),
+        };
+        let known = self.state.read().unwrap_or_else(|e| e.into_inner()).entries.clone();
+        let mut seen = BTreeSet::new();
+        //[None] removes the domain
+        let mut changes = vec![];
+        for path in files {
+            let domain = match path.file_name().and_then(|name| name.to_str()) {
+                Some(domain) => domain.to_string(),
+                None => continue,
+            };
+            let meta = match vfs.metadata(&path) {
+                Ok(meta) if meta.is_file() => meta,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Skipping domain file {} - {}", path.as_path().to_string_lossy(), e);
+                    continue;
+                }
+            };
+            let fingerprint = (meta.modified(), meta.len());
+            seen.insert(domain.clone());
+            if matches!(known.get(&domain), Some((Some(known), _)) if *known == fingerprint) {
+                continue;
+            }
+            let trusted = meta
+                .modified()
+                .is_some_and(|modified| modified + RACY_WINDOW < started);
+            match vfs.read_domain_entry(&domain) {
+                Ok(entry) => changes.push((domain, Some((Some(fingerprint).filter(|_| trusted), entry)))),
+                Err(e) => {
+                    warn!("Skipping domain file {} - {}", path.as_path().to_string_lossy(), e);
+                    changes.push((domain, None));
+                }
+            }
+        }
+        changes.extend(known.keys().filter(|domain| !seen.contains(*domain)).map(|domain| (domain.clone(), None)));
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        for (domain, change) in changes {
+            //an update made through this process while the files were being read is newer than what was read
+            if state.entries.get(&domain) != known.get(&domain) {
+                continue;
+            }
+            match change {
+                Some((fingerprint, entry)) => state.insert(&domain, fingerprint, entry),
+                None => state.remove(&domain),
+            }
+        }
+        if state.stale {
+            state.rebuild();
+        }
+        Ok(())
+    }
+    ///Records that the file of `domain` now contains `entry`, or was deleted if [None]
+    pub fn update(&self, domain: &str, entry: Option<DomainEntry>) {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        match entry {
+            Some(entry) => state.insert(domain, None, entry),
+            None => state.remove(domain),
+        }
+        state.rebuild();
+    }
+    ///The options `domain`'s file resolves to as of the last refresh
+    pub fn get(&self, domain: &str) -> Option<DomainOptions> {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        state.resolved.get(domain).cloned()
+    }
+    ///The entry in `domain`'s file as of the last refresh
+    pub fn entry(&self, domain: &str) -> Option<DomainEntry> {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        state.entries.get(domain).map(|(_, entry)| entry.clone())
+    }
+    ///Every domain serving any version of `service_id`, sorted
+    pub fn domains_for_service(&self, service_id: i64) -> Vec<String> {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        state.by_service.get(&service_id).map(|d| d.iter().cloned().collect()).unwrap_or_default()
+    }
+    ///Every domain serving the given draft or version of `service_id`, sorted
+    pub fn domains_for_version(&self, service_id: i64, version: &str, is_draft: bool) -> Vec<String> {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        state
+            .by_version
+            .get(&(service_id, version.to_string(), is_draft))
+            .map(|d| d.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+///See [Vfs::domains_for_service]
+pub(crate) fn domains_for_service<F: Vfs + ?Sized>(vfs: &F, service_id: i64) -> Result<Vec<String>> {
+    if let Some(index) = vfs.domain_index() {
+        index.refresh(vfs)?;
+        return Ok(index.domains_for_service(service_id));
+    }
+    let mut domains: Vec<_> = vfs
+        .read_domains()?
+        .into_iter()
+        .filter(|(_, options)| options.service_id == service_id)
+        .map(|(domain, _)| domain)
+        .collect();
+    domains.sort();
+    Ok(domains)
+}
+
+///See [Vfs::domains_for_version]
+pub(crate) fn domains_for_version<F: Vfs + ?Sized>(vfs: &F, service_id: i64, version: &str, is_draft: bool) -> Result<Vec<String>> {
+    if let Some(index) = vfs.domain_index() {
+        index.refresh(vfs)?;
+        return Ok(index.domains_for_version(service_id, version, is_draft));
+    }
+    let mut domains: Vec<_> = vfs
+        .read_domains()?
+        .into_iter()
+        .filter(|(_, options)| options.service_id == service_id && options.version == version && options.is_draft == is_draft)
+        .map(|(domain, _)| domain)
+        .collect();
+    domains.sort();
+    Ok(domains)
+}
+
+///Tells the index of `vfs`, if it has one, that `domain` changed
+pub(crate) fn index_update<F: Vfs + ?Sized>(vfs: &F, domain: &str, entry: Option<DomainEntry>) {
+    if let Some(index) = vfs.domain_index() {
+        index.update(domain, entry);
+    }
+}
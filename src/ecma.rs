@@ -0,0 +1,194 @@
+//! Resolving the module specifiers of `import` statements in a version's ECMA scripts.
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash::ContentHash;
+use crate::version::{dir_exists, manifest_key};
+use crate::vfs::{is_not_found, DirEntry, Result, Vfs, VfsErr, VfsPath};
+
+///The import map of a version, at the top of its [ECMA_SUBDIR](crate::vfs::ECMA_SUBDIR)
+pub const IMPORT_MAP_FILE: &str = "import_map.json";
+///Tried in order when a specifier doesn't name a file as is, e.g. `./util` can be `util.js` or `util/index.js`
+const FALLBACKS: [&str; 3] = [".js", ".mjs", "/index.js"];
+///The name of the file an [EcmaManifest] is stored in, at the top of a version next to its ecma directory
+pub const ECMA_MANIFEST_FILE: &str = "ecma_manifest.json";
+
+///A script listed in an [EcmaManifest]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcmaModuleInfo {
+    ///In bytes
+    pub size: u64,
+    pub hash: ContentHash,
+}
+
+///Every `.js` and `.mjs` script of a version keyed by its module name, as returned by [EcmaResolver::resolve].
+///A runtime can key its compiled code by [EcmaModuleInfo::hash] to skip recompiling modules which haven't changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcmaManifest {
+    pub modules: BTreeMap<String, EcmaModuleInfo>,
+}
+
+impl EcmaManifest {
+    pub(crate) fn is_script(module: &str) -> bool {
+        module.ends_with(".js") || module.ends_with(".mjs")
+    }
+}
+
+///Maps bare specifiers such as `lodash` to files in the ecma tree, a subset of the browser's import maps.
+///A key ending in `/` maps every specifier starting with it, e.g. `{"utils/": "lib/utils/"}` resolves `utils/date`
+///to `lib/utils/date`. Targets are relative to the top of the ecma tree, never to the importing file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportMap {
+    #[serde(default)]
+    pub imports: BTreeMap<String, String>,
+}
+
+impl ImportMap {
+    ///The target of `specifier`, an exact match wins over the longest matching prefix
+    pub fn lookup(&self, specifier: &str) -> Option<String> {
+        if let Some(target) = self.imports.get(specifier) {
+            return Some(target.clone());
+        }
+        self.imports
+            .iter()
+            .filter(|(prefix, _)| prefix.ends_with('/') && specifier.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, target)| format!("{}{}", target, &specifier[prefix.len()..]))
+    }
+}
+
+///Resolves `import` specifiers to modules in the [ECMA_SUBDIR](crate::vfs::ECMA_SUBDIR) of one draft or version,
+///for use as the module loader of a JS runtime.
+///Modules are identified by their `/` separated path relative to the ecma directory, e.g. `lib/util.js`.
+///Specifiers starting with `./` or `../` are relative to the importing module, those starting with `/` to the top of
+///the ecma tree and anything else is looked up in the version's [ImportMap]. Nothing outside the ecma tree can be
+///resolved, a specifier with too many `..` fails with [VfsErr::DotPathsNotSupported].
+pub struct EcmaResolver<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    vfs: &'a F,
+    dir: VfsPath,
+    import_map: ImportMap,
+}
+
+impl<'a, F> EcmaResolver<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    ///Loads the version's [IMPORT_MAP_FILE] if it has one, failing with [VfsErr::InvalidImportMap] if it can't be parsed
+    pub fn new(vfs: &'a F, service_id: i64, is_draft: bool, version: &str) -> Result<Self> {
+        let dir = vfs.ecma_dir(service_id, is_draft, version)?;
+        let import_map = match vfs.read_bytes(dir.join(IMPORT_MAP_FILE)?) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| VfsErr::InvalidImportMap(e.to_string()))?,
+            Err(e) if is_not_found(&e) => ImportMap::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(EcmaResolver { vfs, dir, import_map })
+    }
+    pub fn import_map(&self) -> &ImportMap {
+        &self.import_map
+    }
+
+    ///The module `specifier` refers to when imported by the module `referrer`, [None] for an entry point.
+    ///Fails with [VfsErr::ModuleNotFound] if no file matches the specifier, even after adding `.js`, `.mjs` or `/index.js`.
+    pub fn resolve(&self, specifier: &str, referrer: Option<&str>) -> Result<String> {
+        let not_found = || VfsErr::ModuleNotFound {
+            specifier: specifier.to_string(),
+            referrer: referrer.map(|r| r.to_string()),
+        };
+        let path = if specifier.starts_with("./") || specifier.starts_with("../") {
+            //relative to the directory of the importing module
+            let dir = referrer.and_then(|r| r.rsplit_once('/')).map(|(dir, _)| dir).unwrap_or_default();
+            format!("{}/{}", dir, specifier)
+        } else if let Some(path) = specifier.strip_prefix('/') {
+            path.to_string()
+        } else {
+            self.import_map.lookup(specifier).ok_or_else(not_found)?
+        };
+        let module = normalise(&path).ok_or_else(|| VfsErr::DotPathsNotSupported(specifier.to_string()))?;
+        if module.is_empty() {
+            return Err(not_found());
+        }
+        for suffix in std::iter::once("").chain(FALLBACKS) {
+            let module = format!("{}{}", module, suffix);
+            if self.is_file(&module)? {
+                return Ok(module);
+            }
+        }
+        Err(not_found())
+    }
+
+    ///The path of the module `module`, as returned by [EcmaResolver::resolve]
+    pub fn path(&self, module: &str) -> Result<VfsPath> {
+        self.dir.join(module)
+    }
+
+    ///The source of the module `module`, as returned by [EcmaResolver::resolve]
+    pub fn load(&self, module: &str) -> Result<String> {
+        let mut input = self.vfs.read(self.path(module)?)?;
+        let mut source = String::new();
+        input.read_to_string(&mut source).map_err(VfsErr::Io)?;
+        Ok(source)
+    }
+
+    fn is_file(&self, module: &str) -> Result<bool> {
+        match self.vfs.metadata(&self.path(module)?) {
+            Ok(meta) => Ok(meta.is_file()),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+///See [Vfs::ecma_manifest]
+pub(crate) fn ecma_manifest<F: Vfs + ?Sized>(vfs: &F, service_id: i64, is_draft: bool, version: &str) -> Result<EcmaManifest> {
+    if is_draft {
+        return build_manifest(vfs, service_id, is_draft, version);
+    }
+    let file = vfs.version_dir(service_id, is_draft, version)?.join(ECMA_MANIFEST_FILE)?;
+    match vfs.read_bytes(file) {
+        Ok(data) => serde_json::from_slice(&data).map_err(VfsErr::JsonErr),
+        //published before manifests existed, published versions never change so it's built but not written
+        Err(e) if is_not_found(&e) => build_manifest(vfs, service_id, is_draft, version),
+        Err(e) => Err(e),
+    }
+}
+
+fn build_manifest<F: Vfs + ?Sized>(vfs: &F, service_id: i64, is_draft: bool, version: &str) -> Result<EcmaManifest> {
+    if !dir_exists(vfs, &vfs.version_dir(service_id, is_draft, version)?)? {
+        return Err(VfsErr::VersionNotFound(version.to_string()));
+    }
+    let mut manifest = EcmaManifest::default();
+    let dir = vfs.ecma_dir(service_id, is_draft, version)?;
+    if !dir_exists(vfs, &dir)? {
+        return Ok(manifest);
+    }
+    for entry in vfs.dir_stream(dir)? {
+        let DirEntry { rel, path, .. } = entry?;
+        let module = manifest_key(&rel);
+        if !EcmaManifest::is_script(&module) {
+            continue;
+        }
+        let (hash, size) = ContentHash::of_reader(&mut vfs.read(path)?).map_err(VfsErr::Io)?;
+        manifest.modules.insert(module, EcmaModuleInfo { size, hash });
+    }
+    Ok(manifest)
+}
+
+///Removes `.`, `..` and empty components from the `/` separated `path`, [None] if it goes above the top of the tree
+pub(crate) fn normalise(path: &str) -> Option<String> {
+    let mut components = vec![];
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            name => components.push(name),
+        }
+    }
+    Some(components.join("/"))
+}
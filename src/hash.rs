@@ -0,0 +1,78 @@
+//! Content hashes used to detect changes to files in a [Vfs](crate::vfs::Vfs).
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Read;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+///The SHA-256 of a file's content. It is displayed and serialised as lower case hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    pub fn of(data: &[u8]) -> Self {
+        ContentHash(Sha256::digest(data).into())
+    }
+    ///Hashes everything `input` produces, returning the hash and the number of bytes read
+    pub fn of_reader<R: Read + ?Sized>(input: &mut R) -> std::io::Result<(Self, u64)> {
+        let mut hasher = Sha256::new();
+        let mut buffer = [0; 8192];
+        let mut len = 0;
+        loop {
+            let n = input.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[0..n]);
+            len += n as u64;
+        }
+        Ok((ContentHash(hasher.finalize().into()), len))
+    }
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for ContentHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ContentHash({})", self)
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(format!("Expected 64 hex characters, got {}", s));
+        }
+        let mut hash = [0; 32];
+        for (idx, b) in hash.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[idx * 2..idx * 2 + 2], 16).map_err(|e| format!("{} in {}", e, s))?;
+        }
+        Ok(ContentHash(hash))
+    }
+}
+
+impl Serialize for ContentHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -1,3 +1,19 @@
 pub mod vfs;
+#[cfg(feature = "async")]
+pub mod async_vfs;
+pub mod atomic;
+pub mod cache;
+pub mod diff;
+pub mod domain;
+pub mod ecma;
+pub mod hash;
+pub mod schema;
+pub mod validate;
+pub mod version;
+pub mod watch;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod beneath;
 pub use vfs::MemoryVfs;
 pub use vfs::FilesystemVfs;
+pub use vfs::VfsPath;
+pub use cache::CachingVfs;
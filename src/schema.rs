@@ -0,0 +1,125 @@
+//! Loading a version's `schema.xml` together with every file it imports.
+use indexmap::IndexMap;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::ecma::normalise;
+use crate::vfs::{is_not_found, Result, Vfs, VfsErr};
+
+///The file every schema bundle starts from
+pub const SCHEMA_FILE: &str = "schema.xml";
+///The attribute naming a file to import, e.g. `<pipeline import="pipeline_register.xml"/>`
+pub const IMPORT_ATTRIBUTE: &str = "import";
+
+///The value of every [IMPORT_ATTRIBUTE] in `xml`, in document order.
+///Only the syntax needed to find the attributes is checked, e.g. mismatched end tags are ignored.
+pub fn imports(file: &str, xml: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().check_end_names = false;
+    reader.config_mut().allow_unmatched_ends = true;
+    let mut imports = vec![];
+    loop {
+        let event = reader.read_event().map_err(|e| VfsErr::Xml {
+            file: file.to_string(),
+            message: e.to_string(),
+        })?;
+        match event {
+            Event::Start(e) | Event::Empty(e) => {
+                for attr in e.attributes() {
+                    let attr = attr.map_err(|e| VfsErr::Xml {
+                        file: file.to_string(),
+                        message: e.to_string(),
+                    })?;
+                    if attr.key.as_ref() != IMPORT_ATTRIBUTE.as_bytes() {
+                        continue;
+                    }
+                    let value = attr.unescape_value().map_err(|e| VfsErr::Xml {
+                        file: file.to_string(),
+                        message: e.to_string(),
+                    })?;
+                    imports.push(value.trim().to_string());
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(imports)
+}
+
+///The bundle key of `import`, so `./types.xml` and `types.xml` are loaded once and seen to be the same file
+pub(crate) fn import_key(import: &str) -> Result<String> {
+    normalise(import).ok_or_else(|| VfsErr::DotPathsNotSupported(import.to_string()))
+}
+
+///See [Vfs::load_schema_bundle]
+pub(crate) fn load_schema_bundle<F: Vfs + ?Sized>(
+    vfs: &F,
+    service_id: i64,
+    is_draft: bool,
+    version: &str,
+) -> Result<IndexMap<String, String>> {
+    let mut loader = Loader {
+        vfs,
+        service_id,
+        is_draft,
+        version,
+        stack: vec![],
+        bundle: IndexMap::new(),
+    };
+    loader.load(SCHEMA_FILE, None)?;
+    Ok(loader.bundle)
+}
+
+struct Loader<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    vfs: &'a F,
+    service_id: i64,
+    is_draft: bool,
+    version: &'a str,
+    ///The files being loaded, from [SCHEMA_FILE] down to the current one
+    stack: Vec<String>,
+    bundle: IndexMap<String, String>,
+}
+
+impl<'a, F> Loader<'a, F>
+    where
+        F: Vfs + ?Sized,
+{
+    fn load(&mut self, file: &str, importer: Option<&str>) -> Result<()> {
+        if self.stack.iter().any(|f| f == file) {
+            let mut cycle = self.stack.clone();
+            cycle.push(file.to_string());
+            return Err(VfsErr::ImportCycle(cycle.join(" -> ")));
+        }
+        if self.bundle.contains_key(file) {
+            return Ok(());
+        }
+        let xml = match self.vfs.read_schema_file(self.service_id, self.is_draft, self.version, file) {
+            Ok(xml) => xml,
+            Err(e) if is_not_found(&e) => {
+                return Err(match importer {
+                    Some(importer) => VfsErr::MissingImport {
+                        importer: importer.to_string(),
+                        import: file.to_string(),
+                    },
+                    None => VfsErr::SchemaFileNotFound(file.to_string()),
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        let children = imports(file, &xml)?
+            .iter()
+            .map(|import| import_key(import))
+            .collect::<Result<Vec<_>>>()?;
+        self.bundle.insert(file.to_string(), xml);
+        self.stack.push(file.to_string());
+        for child in children {
+            self.load(&child, Some(file))?;
+        }
+        self.stack.pop();
+        Ok(())
+    }
+}
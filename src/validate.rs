@@ -0,0 +1,111 @@
+//! Optional checks on schema files, see [PublishOptions::validate_schema](crate::version::PublishOptions::validate_schema)
+//! and [BoundVfs::write_schema_file](crate::vfs::BoundVfs::write_schema_file).
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::schema::{import_key, imports};
+use crate::vfs::{is_not_found, Result, Vfs, VfsErr};
+
+///Checks `xml` is a well-formed XML document: tags are balanced and correctly nested, attributes are valid and unique
+///and there is exactly one root element. Fails with [VfsErr::InvalidSchema] pointing at the first problem found.
+pub fn check_well_formed(file: &str, xml: &str) -> Result<()> {
+    let mut reader = Reader::from_str(xml);
+    let mut depth = 0usize;
+    let mut seen_root = false;
+    loop {
+        //where the next event starts, which is where any problem with it is reported
+        let position = reader.buffer_position();
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => return Err(invalid(file, xml, reader.error_position(), e.to_string())),
+        };
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                if depth == 0 && seen_root {
+                    return Err(invalid(file, xml, position, "more than one root element".to_string()));
+                }
+                for attr in e.attributes() {
+                    if let Err(e) = attr {
+                        return Err(invalid(file, xml, position, e.to_string()));
+                    }
+                }
+                seen_root = true;
+                if matches!(event, Event::Start(_)) {
+                    depth += 1;
+                }
+            }
+            Event::End(_) => depth = depth.saturating_sub(1),
+            Event::Text(text) if depth == 0 => {
+                //reported at the first character which isn't whitespace
+                if let Some(start) = text.iter().position(|b| !b.is_ascii_whitespace()) {
+                    let position = position + start as u64;
+                    return Err(invalid(file, xml, position, "text outside of the root element".to_string()));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        return Err(invalid(file, xml, xml.len() as u64, "unclosed element at end of file".to_string()));
+    }
+    if !seen_root {
+        return Err(invalid(file, xml, 0, "no root element".to_string()));
+    }
+    Ok(())
+}
+
+///Runs [check_well_formed] over `xml`, which is about to be written to the draft's schema file `name`, and every file
+///it imports, directly or not, which is already in the draft. Imports which don't exist yet are skipped.
+pub(crate) fn check_with_imports<F: Vfs + ?Sized>(vfs: &F, service_id: i64, draft: &str, name: &str, xml: &str) -> Result<()> {
+    check_well_formed(name, xml)?;
+    let mut seen = HashSet::from([import_key(name)?]);
+    let mut pending = imports(name, xml)?;
+    while let Some(file) = pending.pop() {
+        let file = import_key(&file)?;
+        if !seen.insert(file.clone()) {
+            continue;
+        }
+        let xml = match vfs.read_schema_file(service_id, true, draft, &file) {
+            Ok(xml) => xml,
+            Err(e) if is_not_found(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        check_well_formed(&file, &xml)?;
+        pending.extend(imports(&file, &xml)?);
+    }
+    Ok(())
+}
+
+///Runs [check_well_formed] over every file in a bundle from [Vfs::load_schema_bundle]
+pub fn check_bundle(bundle: &IndexMap<String, String>) -> Result<()> {
+    for (file, xml) in bundle {
+        check_well_formed(file, xml)?;
+    }
+    Ok(())
+}
+
+fn invalid(file: &str, xml: &str, offset: u64, message: String) -> VfsErr {
+    let (line, column) = line_column(xml, offset as usize);
+    VfsErr::InvalidSchema {
+        file: file.to_string(),
+        line,
+        column,
+        message,
+    }
+}
+
+///The 1 based line and column, in characters, of the byte `offset` in `text`
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
@@ -0,0 +1,367 @@
+//! Listing, publishing and deleting the drafts and versions of a service.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Write};
+use std::path::{Component, Path};
+use std::time::SystemTime;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::atomic::{is_atomic_tmp, ATOMIC_TMP_PREFIX};
+use crate::domain::{index_update, parse_domain_file, DomainEntry, DomainFile};
+use crate::ecma::{EcmaManifest, EcmaModuleInfo, ECMA_MANIFEST_FILE};
+use crate::hash::ContentHash;
+use crate::schema::SCHEMA_FILE;
+use crate::vfs::{is_not_found, unique_name, DirEntry, DRAFTS_SUBDIR, ECMA_SUBDIR, VERSIONS_SUBDIR, Result, Vfs, VfsErr, VfsOpenOptions, VfsPath};
+
+///The name of the file [Vfs::publish_draft] writes the [VersionManifest] to, at the top of the new version
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+///What [Vfs::publish_draft_with] does besides copying the draft
+#[derive(Debug, Clone)]
+pub struct PublishOptions {
+    ///Refuse to publish a draft without a `schema.xml`
+    pub require_schema: bool,
+    ///Write a [VersionManifest] to [MANIFEST_FILE] and an [EcmaManifest] to [ECMA_MANIFEST_FILE] in the new version
+    pub write_manifest: bool,
+    ///Point every domain which serves the draft at the new version
+    pub update_domains: bool,
+    ///Refuse to publish a draft whose `schema.xml`, or any file it imports, isn't well-formed XML,
+    ///see [check_bundle](crate::validate::check_bundle)
+    pub validate_schema: bool,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        PublishOptions {
+            require_schema: true,
+            write_manifest: true,
+            update_domains: true,
+            validate_schema: false,
+        }
+    }
+}
+
+///The hash of every file in a version, keyed by its `/` separated path relative to the version directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionManifest {
+    pub files: BTreeMap<String, ContentHash>,
+}
+
+///The outcome of [Vfs::publish_draft]
+#[derive(Debug)]
+pub struct PublishedVersion {
+    ///The directory of the new version
+    pub dir: VfsPath,
+    ///The hash of every file copied from the draft, whether or not it was written to [MANIFEST_FILE]
+    pub manifest: VersionManifest,
+    ///The domains which were pointed at the new version
+    pub domains: Vec<String>,
+}
+
+///A summary of a draft or published version, see [Vfs::list_versions]
+#[derive(Debug, Clone)]
+pub struct VersionInfo {
+    pub name: String,
+    pub is_draft: bool,
+    ///When the version's directory was created or, if the backend doesn't record that, its oldest file
+    pub created: Option<SystemTime>,
+    ///The last time the directory or any file in it was changed
+    pub modified: Option<SystemTime>,
+    pub file_count: usize,
+    ///The sum of the size of every file, in bytes
+    pub total_size: u64,
+    pub has_schema: bool,
+}
+
+///Checks `name` can be used as a single directory name for a draft or version
+pub(crate) fn validate_version_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(VfsErr::InvalidVersionName(name.to_string())),
+    }
+}
+
+///True if `dir` can be listed, false if it doesn't exist
+pub(crate) fn dir_exists<F: Vfs + ?Sized>(vfs: &F, dir: &VfsPath) -> Result<bool> {
+    match vfs.read_dir(dir) {
+        Ok(_) => Ok(true),
+        Err(e) if is_not_found(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+///See [Vfs::publish_draft_with].
+///The draft is copied into a hidden staging directory next to the version which is then renamed to the version,
+///so a version is either missing or complete, never half copied. Being a sibling, the rename never crosses filesystems.
+pub(crate) fn publish_draft<F: Vfs + ?Sized>(
+    vfs: &F,
+    service_id: i64,
+    draft: &str,
+    new_version: &str,
+    opts: &PublishOptions,
+) -> Result<PublishedVersion> {
+    validate_version_name(draft)?;
+    validate_version_name(new_version)?;
+    let draft_dir = vfs.version_dir(service_id, true, draft)?;
+    let target = vfs.version_dir(service_id, false, new_version)?;
+    let exists = || VfsErr::VersionExists(target.as_path().to_string_lossy().to_string());
+    //fails early rather than after copying the draft, the rename below is what stops a concurrent publish
+    if dir_exists(vfs, &target)? {
+        return Err(exists());
+    }
+    if opts.require_schema {
+        let schema = draft_dir.join(SCHEMA_FILE)?;
+        if !vfs.exists(&schema)? {
+            return Err(VfsErr::SchemaFileNotFound(schema.as_path().to_string_lossy().to_string()));
+        }
+    }
+    if opts.validate_schema {
+        crate::validate::check_bundle(&vfs.load_schema_bundle(service_id, true, draft)?)?;
+    }
+    let versions = target
+        .parent_unchecked()
+        .ok_or_else(|| VfsErr::PathEscapesRoot(target.as_path().to_string_lossy().to_string()))?;
+    vfs.create_dir_all(&versions)?;
+    let staging = versions.join(format!("{}{}.{}", ATOMIC_TMP_PREFIX, new_version, unique_name("publish")))?;
+    let mut created = vec![];
+    let manifest = match stage(vfs, &draft_dir, &staging, opts, &mut created) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            remove_staged(vfs, &staging, created);
+            return Err(e);
+        }
+    };
+    //renaming onto a version which isn't empty fails, a publish which won the race since the check above is kept
+    let res = vfs.rename(&staging, &target).map_err(|e| match e {
+        VfsErr::Io(e) if matches!(e.kind(), ErrorKind::DirectoryNotEmpty | ErrorKind::AlreadyExists) => exists(),
+        e => e,
+    });
+    if let Err(e) = res {
+        remove_staged(vfs, &staging, created);
+        return Err(e);
+    }
+    vfs.sync_dir(&versions)?;
+    let domains = if opts.update_domains {
+        repoint_domains(vfs, service_id, draft, new_version)?
+    } else {
+        vec![]
+    };
+    Ok(PublishedVersion {
+        dir: target,
+        manifest,
+        domains,
+    })
+}
+
+///Copies every file in `draft_dir` to `staging`, recording everything it creates in `created` so it can be undone
+fn stage<F: Vfs + ?Sized>(
+    vfs: &F,
+    draft_dir: &VfsPath,
+    staging: &VfsPath,
+    opts: &PublishOptions,
+    created: &mut Vec<(VfsPath, bool)>,
+) -> Result<VersionManifest> {
+    vfs.create_dir_all(staging)?;
+    let mut manifest = VersionManifest::default();
+    let mut ecma = EcmaManifest::default();
+    let files: Vec<_> = vfs.dir_stream(draft_dir.clone())?.collect::<Result<_>>()?;
+    for DirEntry { rel, path: source, .. } in files {
+        //generated for the new version below, a draft re-created from a published version has stale copies
+        if rel == Path::new(MANIFEST_FILE) || rel == Path::new(ECMA_MANIFEST_FILE) {
+            continue;
+        }
+        let dest = staging.join(&rel)?;
+        if let Some(parent) = dest.parent_unchecked().filter(|p| p != staging) {
+            vfs.create_dir_all(&parent)?;
+            let mut dir = Some(parent);
+            while let Some(d) = dir.filter(|d| d != staging) {
+                dir = d.parent_unchecked();
+                created.push((d, true));
+            }
+        }
+        let data = vfs.read_bytes(source)?;
+        write_new(vfs, &dest, &data)?;
+        created.push((dest, false));
+        let hash = ContentHash::of(&data);
+        if let Ok(module) = rel.strip_prefix(ECMA_SUBDIR).map(manifest_key) {
+            if EcmaManifest::is_script(&module) {
+                let size = data.len() as u64;
+                ecma.modules.insert(module, EcmaModuleInfo { size, hash });
+            }
+        }
+        manifest.files.insert(manifest_key(&rel), hash);
+    }
+    if opts.write_manifest {
+        let dest = staging.join(MANIFEST_FILE)?;
+        let data = serde_json::to_vec_pretty(&manifest).map_err(VfsErr::JsonErr)?;
+        write_new(vfs, &dest, &data)?;
+        created.push((dest, false));
+        let dest = staging.join(ECMA_MANIFEST_FILE)?;
+        let data = serde_json::to_vec_pretty(&ecma).map_err(VfsErr::JsonErr)?;
+        write_new(vfs, &dest, &data)?;
+        created.push((dest, false));
+    }
+    Ok(manifest)
+}
+
+fn write_new<F: Vfs + ?Sized>(vfs: &F, dest: &VfsPath, data: &[u8]) -> Result<()> {
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create_new(true);
+    let mut file = vfs.open_with(dest.clone(), opts)?;
+    file.write_all(data).map_err(VfsErr::Io)?;
+    file.sync_all()
+}
+
+///`rel` with `/` separators whatever the OS, as used for the keys of a [VersionManifest]
+pub(crate) fn manifest_key(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+///Best effort removal of a failed publish's staging directory, deepest entries first
+fn remove_staged<F: Vfs + ?Sized>(vfs: &F, staging: &VfsPath, mut created: Vec<(VfsPath, bool)>) {
+    //sorted, a directory comes before everything in it so walking backwards empties it before removing it
+    created.push((staging.clone(), true));
+    created.sort();
+    created.dedup();
+    for (path, is_dir) in created.into_iter().rev() {
+        let res = if is_dir { vfs.remove_dir(&path) } else { vfs.remove_file(&path) };
+        if let Err(e) = res {
+            warn!("Failed to clean up {} after a failed publish - {}", path.as_path().to_string_lossy(), e);
+        }
+    }
+}
+
+///Atomically rewrites every domain serving `draft` of `service_id` to serve `new_version` instead
+fn repoint_domains<F: Vfs + ?Sized>(vfs: &F, service_id: i64, draft: &str, new_version: &str) -> Result<Vec<String>> {
+    let domains = vfs.domains_for_version(service_id, draft, true)?;
+    let mut updated = vec![];
+    for domain in domains {
+        let file = vfs.domain_file(&domain)?;
+        let data = match vfs.read_bytes(file.clone()) {
+            Ok(data) => data,
+            Err(e) if is_not_found(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        //aliases follow the domain they point at, wildcards and the rest of the options are kept
+        let mut options = match parse_domain_file(&data)?.entry {
+            DomainEntry::Options(options) => options,
+            DomainEntry::Alias { .. } => continue,
+        };
+        if options.service_id != service_id || options.version != draft || !options.is_draft {
+            continue;
+        }
+        options.version = new_version.to_string();
+        options.is_draft = false;
+        let rewritten = DomainFile::new(DomainEntry::Options(options.clone())).to_json()?;
+        //a domain rebound since it was read keeps where it was rebound to
+        let mut writer = vfs.atomic_writer(file)?.expect_previous(Some(ContentHash::of(&data)));
+        writer.write_all(&rewritten).map_err(VfsErr::Io)?;
+        match writer.commit() {
+            Ok(()) => {}
+            Err(VfsErr::Conflict(path)) => {
+                warn!("Not pointing {} at {} - {} changed while publishing", domain, new_version, path);
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        index_update(vfs, &domain, Some(DomainEntry::Options(options.clone())));
+        updated.push(domain);
+    }
+    Ok(updated)
+}
+
+///See [Vfs::list_versions]
+pub(crate) fn list_versions<F: Vfs + ?Sized>(vfs: &F, service_id: i64) -> Result<Vec<VersionInfo>> {
+    let mut versions = vec![];
+    for (is_draft, subdir) in [(true, DRAFTS_SUBDIR), (false, VERSIONS_SUBDIR)] {
+        let dir = vfs.resolve(format!("{}/{}", service_id, subdir).as_str())?;
+        let entries = match vfs.read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if is_not_found(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        //a backend may list nested entries, only the first component under dir is a version
+        let names: BTreeSet<String> = entries
+            .filter_map(|path| {
+                let rel = path.as_path().strip_prefix(dir.as_path()).ok()?;
+                match rel.components().next() {
+                    //publishes in progress stage into hidden siblings of their versions
+                    Some(Component::Normal(name)) if !is_atomic_tmp(Path::new(name)) => {
+                        name.to_str().map(|name| name.to_string())
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        for name in names {
+            let version_dir = dir.join(&name)?;
+            //one bad entry, e.g. a symlink in hardened mode, doesn't stop the rest being listed
+            let info = vfs.metadata(&version_dir).and_then(|meta| match meta.is_dir() {
+                true => version_info(vfs, name, is_draft, &version_dir, meta.created(), meta.modified()).map(Some),
+                false => Ok(None),
+            });
+            match info {
+                Ok(info) => versions.extend(info),
+                //deleted since it was listed
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => warn!("Skipping version {} - {}", version_dir.as_path().to_string_lossy(), e),
+            }
+        }
+    }
+    Ok(versions)
+}
+
+fn version_info<F: Vfs + ?Sized>(
+    vfs: &F,
+    name: String,
+    is_draft: bool,
+    dir: &VfsPath,
+    created: Option<SystemTime>,
+    mut modified: Option<SystemTime>,
+) -> Result<VersionInfo> {
+    let mut oldest: Option<SystemTime> = None;
+    let mut file_count = 0;
+    let mut total_size = 0;
+    let mut has_schema = false;
+    for entry in vfs.dir_stream(dir.clone())? {
+        let DirEntry { rel, metadata: meta, .. } = entry?;
+        file_count += 1;
+        total_size += meta.len();
+        has_schema |= rel == Path::new(SCHEMA_FILE);
+        if let Some(created) = meta.created() {
+            oldest = Some(oldest.map_or(created, |oldest| oldest.min(created)));
+        }
+        modified = match (modified, meta.modified()) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+    Ok(VersionInfo {
+        name,
+        is_draft,
+        created: created.or(oldest),
+        modified,
+        file_count,
+        total_size,
+        has_schema,
+    })
+}
+
+///See [Vfs::delete_version]
+pub(crate) fn delete_version<F: Vfs + ?Sized>(vfs: &F, service_id: i64, is_draft: bool, version: &str) -> Result<()> {
+    validate_version_name(version)?;
+    let dir = vfs.version_dir(service_id, is_draft, version)?;
+    let in_use = vfs.domains_for_version(service_id, version, is_draft)?;
+    if !in_use.is_empty() {
+        return Err(VfsErr::VersionInUse(in_use.join(", ")));
+    }
+    vfs.remove_dir_all(&dir).map_err(|e| match is_not_found(&e) {
+        true => VfsErr::VersionNotFound(version.to_string()),
+        false => e,
+    })
+}
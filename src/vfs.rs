@@ -1,16 +1,28 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
 use std::fmt::{Debug, Formatter};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use bytes::BufMut;
+use bytes::{Buf, Bytes};
+use indexmap::IndexMap;
 use log::warn;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::atomic::{is_atomic_tmp, AtomicWriter};
+use crate::diff::{VersionDiff, VersionRef};
+use crate::domain::{DomainEntry, DomainIndex};
+use crate::ecma::{EcmaManifest, EcmaResolver};
+use crate::version::{PublishOptions, PublishedVersion, VersionInfo};
+use crate::watch::{Notifier, WatchTargets, Watcher};
+
 pub const DOMAINS_SUBDIR: &str = "domains";
 pub const RESOURCES_SUBDIR: &str = "files";
 pub const TMP_SUBDIR: &str = ".tmp";
@@ -18,6 +30,9 @@ pub const VERSIONS_SUBDIR: &str = "versions";
 pub const DRAFTS_SUBDIR: &str = "drafts";
 pub const ECMA_SUBDIR: &str = "ecma";
 pub const PLUGINS_SUBDIR: &str = "plugins";
+///A good [FilesystemVfs::with_mmap_threshold] for roots whose files are only ever replaced with a rename,
+///smaller files are quicker to read than to map
+pub const MMAP_THRESHOLD: u64 = 256 * 1024;
 
 pub type Result<T> = std::result::Result<T, VfsErr>;
 
@@ -41,13 +56,379 @@ pub enum VfsErr {
     StripPrefixErr(std::path::StripPrefixError),
     #[error("IO error - {0}")]
     Utf8(std::string::FromUtf8Error),
+    #[error("Path escapes the root directory - {0}")]
+    PathEscapesRoot(String),
+    #[error("Path belongs to a different service - {0}")]
+    ServiceMismatch(String),
+    #[error("File was changed by someone else - {0}")]
+    Conflict(String),
+    #[error("Version already exists - {0}")]
+    VersionExists(String),
+    #[error("Invalid version name - {0}")]
+    InvalidVersionName(String),
+    #[error("Version is still served by domains - {0}")]
+    VersionInUse(String),
+    #[error("Version not found - {0}")]
+    VersionNotFound(String),
+    #[error("Published versions can't be changed - {0}")]
+    VersionIsPublished(String),
+    #[error("Invalid domain name - {0}")]
+    InvalidDomain(String),
+    #[error("Domain aliases form a loop - {0}")]
+    DomainLoop(String),
+    #[error("Domain is an alias - {0}")]
+    DomainIsAlias(String),
+    #[error("Invalid domain file - {0}")]
+    InvalidDomainFile(String),
+    #[error("Error parsing XML in {file} - {message}")]
+    Xml { file: String, message: String },
+    #[error("Schema imports form a cycle - {0}")]
+    ImportCycle(String),
+    #[error("File {import} imported by {importer} not found")]
+    MissingImport { importer: String, import: String },
+    #[error("Invalid schema {file} at line {line}, column {column} - {message}")]
+    InvalidSchema {
+        file: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("Module not found - {specifier}")]
+    ModuleNotFound { specifier: String, referrer: Option<String> },
+    #[error("Invalid import map - {0}")]
+    InvalidImportMap(String),
+}
+
+///How a domain should be served over TLS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsHint {
+    ///Serve both HTTP and HTTPS
+    Allow,
+    ///Refuse plain HTTP requests
+    Require,
+    ///Redirect plain HTTP requests to HTTPS
+    Redirect,
 }
 
-#[derive(Debug, Deserialize)]
+///The options of a domain, i.e. which version of which service it serves and how.
+///Everything but `service_id` and `version` is optional in a domain file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DomainOptions {
     pub service_id: i64,
     pub version: String,
+    #[serde(default)]
     pub is_draft: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsHint>,
+    ///When true the domain is in maintenance and requests shouldn't reach the service
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub maintenance: bool,
+    ///Headers added to every response from the domain
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl DomainOptions {
+    pub fn new(service_id: i64, version: &str, is_draft: bool) -> Self {
+        DomainOptions {
+            service_id,
+            version: version.to_string(),
+            is_draft,
+            tls: None,
+            maintenance: false,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+///The options used to [Vfs::open_with] a file. It mirrors [std::fs::OpenOptions] but, unlike it,
+///the flags can be read back which lets backends other than the OS filesystem honour them.
+#[derive(Debug, Clone, Default)]
+pub struct VfsOpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl VfsOpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+    pub fn is_write(&self) -> bool {
+        self.write
+    }
+    pub fn is_append(&self) -> bool {
+        self.append
+    }
+    pub fn is_truncate(&self) -> bool {
+        self.truncate
+    }
+    pub fn is_create(&self) -> bool {
+        self.create
+    }
+    pub fn is_create_new(&self) -> bool {
+        self.create_new
+    }
+    pub fn to_std(&self) -> OpenOptions {
+        let mut opts = OpenOptions::new();
+        opts.read(self.read)
+            .write(self.write)
+            .append(self.append)
+            .truncate(self.truncate)
+            .create(self.create)
+            .create_new(self.create_new);
+        opts
+    }
+    ///The `open(2)` flags equivalent to these options, following the same rules as [std::fs::OpenOptions].
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn oflags(&self) -> rustix::fs::OFlags {
+        use rustix::fs::OFlags;
+        let writable = self.write || self.append;
+        let mut flags = match (self.read, writable) {
+            (true, true) => OFlags::RDWR,
+            (false, true) => OFlags::WRONLY,
+            _ => OFlags::RDONLY,
+        };
+        if self.append {
+            flags |= OFlags::APPEND;
+        }
+        if self.create_new {
+            flags |= OFlags::CREATE | OFlags::EXCL;
+        } else {
+            if self.create {
+                flags |= OFlags::CREATE;
+            }
+            if self.truncate {
+                flags |= OFlags::TRUNC;
+            }
+        }
+        flags
+    }
+}
+
+///What a path is, see [VfsMetadata::kind]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsFileKind {
+    File,
+    Dir,
+    ///Only seen when a backend doesn't follow symlinks, [FilesystemVfs::hardened] refuses them instead
+    Symlink,
+    ///e.g. a socket or a named pipe
+    Other,
+}
+
+///What a [Vfs] knows about a file or directory, see [Vfs::metadata]
+#[derive(Debug, Clone)]
+pub struct VfsMetadata {
+    len: u64,
+    kind: VfsFileKind,
+    readonly: bool,
+    modified: Option<SystemTime>,
+    created: Option<SystemTime>,
+}
+
+impl VfsMetadata {
+    ///The size of a file in bytes, 0 for directories in backends that don't give them a size
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn kind(&self) -> VfsFileKind {
+        self.kind
+    }
+    pub fn is_dir(&self) -> bool {
+        self.kind == VfsFileKind::Dir
+    }
+    pub fn is_file(&self) -> bool {
+        self.kind == VfsFileKind::File
+    }
+    ///True if the file can't be written to, always false for backends without permissions
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+    ///When the content was last changed, if the backend records it
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+    ///When the file was created, if the backend or OS records it
+    pub fn created(&self) -> Option<SystemTime> {
+        self.created
+    }
+}
+
+impl From<fs::Metadata> for VfsMetadata {
+    fn from(m: fs::Metadata) -> Self {
+        let file_type = m.file_type();
+        let kind = if file_type.is_file() {
+            VfsFileKind::File
+        } else if file_type.is_dir() {
+            VfsFileKind::Dir
+        } else if file_type.is_symlink() {
+            VfsFileKind::Symlink
+        } else {
+            VfsFileKind::Other
+        };
+        VfsMetadata {
+            len: m.len(),
+            kind,
+            readonly: m.permissions().readonly(),
+            modified: m.modified().ok(),
+            created: m.created().ok(),
+        }
+    }
+}
+
+///True if `e` says the path, or one of its parents, doesn't exist, e.g. `a.txt/b.txt` when `a.txt` is a file
+pub(crate) fn is_not_found(e: &VfsErr) -> bool {
+    match e {
+        VfsErr::FileNotFound(_) => true,
+        VfsErr::Io(e) => matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory),
+        _ => false,
+    }
+}
+
+///A file name starting with `prefix` which is unique within this process and very unlikely to clash with another
+pub(crate) fn unique_name(prefix: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!(
+        "{}-{}-{}-{}",
+        prefix,
+        std::process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+///Checks that every component of `path` is a plain name.
+///Root, prefix (e.g. `C:`), `.` and `..` components are rejected but names which merely contain dots
+///such as `my..backup.txt` or `v1./x` are fine.
+pub fn validate_relative(path: &Path) -> Result<()> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => {}
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(VfsErr::AbsolutePathNotSupported(path.to_string_lossy().to_string()));
+            }
+            Component::CurDir | Component::ParentDir => {
+                return Err(VfsErr::DotPathsNotSupported(path.to_string_lossy().to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+///See [Vfs::resolve]
+pub(crate) fn resolve_in(root: &Path, child: &str) -> Result<VfsPath> {
+    let child_path = Path::new(child);
+    //VERY important - root.join below is not safe if child is absolute
+    //because join replaces root with child if child is absolute
+    validate_relative(child_path)?;
+    let resolved = root.join(child_path);
+    //note we don't call resolved.canonicalize() because we don't want to hit the file system
+    //this resolve is used in all implementations of the Vfs which is not necessarily resolved from disk
+    validate_within(root, &resolved)?;
+    let service_id = match child_path.components().next() {
+        Some(Component::Normal(first)) => first.to_str().and_then(|id| id.parse::<i64>().ok()),
+        _ => None,
+    };
+    Ok(VfsPath::new(resolved, service_id))
+}
+
+///Checks that `path` is `root` or is inside it.
+///The comparison is done on path components, not strings, so `/srv/services2` is not inside `/srv/services`.
+pub fn validate_within(root: &Path, path: &Path) -> Result<()> {
+    match path.strip_prefix(root) {
+        Ok(rest) => validate_relative(rest),
+        //the path broke out from under root, don't allow it to continue
+        Err(_) => Err(VfsErr::DotPathsNotSupported(path.to_string_lossy().to_string())),
+    }
+}
+
+///A path which is known to be under the root of a [Vfs].
+///It can only be created by [Vfs::resolve] and the helpers built on it (e.g. [BoundVfs::resolve_resource]),
+///so a path which hasn't been checked can't be passed to [Vfs::read], [Vfs::open_with] or [Vfs::read_dir].
+///It also carries the ID of the service which owns it, if any. Paths outside a service directory, such as domain files, have no service.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VfsPath {
+    path: PathBuf,
+    service_id: Option<i64>,
+}
+
+impl VfsPath {
+    ///Callers MUST have checked `path` is under the root first
+    pub(crate) fn new(path: PathBuf, service_id: Option<i64>) -> Self {
+        Self { path, service_id }
+    }
+    pub fn as_path(&self) -> &Path {
+        &self.path
+    }
+    pub fn into_path_buf(self) -> PathBuf {
+        self.path
+    }
+    pub fn service_id(&self) -> Option<i64> {
+        self.service_id
+    }
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.path.file_name()
+    }
+    ///Appends the relative path `child` to this one, it must not contain root, `.` or `..` components.
+    pub fn join<P: AsRef<Path>>(&self, child: P) -> Result<VfsPath> {
+        validate_relative(child.as_ref())?;
+        Ok(VfsPath::new(self.path.join(child), self.service_id))
+    }
+    ///The parent directory of this path, callers MUST check it is still under the root
+    pub(crate) fn parent_unchecked(&self) -> Option<VfsPath> {
+        self.path
+            .parent()
+            .map(|parent| VfsPath::new(parent.to_path_buf(), self.service_id))
+    }
+}
+
+impl AsRef<Path> for VfsPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
 }
 
 ///[Vfs] i.e. virtual file system is specifically designed to constrain access to the file system via API requests
@@ -59,7 +440,8 @@ pub struct DomainOptions {
 /// ```yaml
 /// services:
 ///   domains:
-///     my-api.apps.hypi.app - file name is the domain, line 1 is the service ID, line 2 is the version
+///     my-api.apps.hypi.app - file name is the domain, the content is a JSON DomainFile with the service ID and version
+///     "*.customer.example.com" - a wildcard serving every sub-domain without a file of its own
 ///   service1:
 ///     files:
 ///       file1.png - the static files are served from here but permission checks are done before serving
@@ -77,57 +459,99 @@ pub struct DomainOptions {
 pub trait Vfs: Sync + Send {
     ///A base directory against which all paths are [resolve]d.
     fn root(&self) -> &PathBuf;
-    fn resolve(&self, child: &str) -> Result<PathBuf> {
-        let root = self.root();
-        let child_path = Path::new(child);
-        //VERY important - root.join below is not safe if child is absolute
-        //because join replaces root with child if child is absolute
-        if child_path.is_absolute() {
-            Err(VfsErr::AbsolutePathNotSupported(child.to_owned()))
-        } else if child.contains("./") || child.contains("..") {
-            Err(VfsErr::DotPathsNotSupported(child.to_owned()))
-        } else {
-            let resolved = root.join(child_path);
-            let res_str = resolved.to_string_lossy().to_string();
-            //note we don't call resolved.canonicalize() because we don't want to hit the file system
-            //this resolve is used in all implementations of the Vfs which is not necessarily resolved from disk
-            if res_str.starts_with(&root.to_string_lossy().to_string())
-            /* root.to_string_lossy().to_string().contains(&res_str)*/
-            {
-                Ok(resolved)
-            } else {
-                //somehow the resolved path broke out from under root, don't allow it to continue
-                Err(VfsErr::DotPathsNotSupported(child.to_owned()))
-            }
+    ///Resolves `child` against [root]. This is the only way to get a [VfsPath] from a string.
+    ///If the first component of `child` is a number it is taken to be the ID of the service owning the path.
+    fn resolve(&self, child: &str) -> Result<VfsPath> {
+        resolve_in(self.root(), child)
+    }
+    ///Ensures an already resolved, absolute path is [root] or somewhere in its sub-tree.
+    ///Implementations call this before touching any path they're given.
+    fn check_path(&self, path: &Path) -> Result<()> {
+        validate_within(self.root(), path)
+    }
+    ///When true, the directories returned by [resource_dir], [plugins_dir] and [tmp_dir] aren't created on every lookup.
+    ///Instead [open_with] creates the parent directories of a file the first time it is created.
+    fn lazy_dirs(&self) -> bool {
+        false
+    }
+    ///Creates `dir` and any of its parents which don't exist yet
+    fn create_dir_all(&self, dir: &VfsPath) -> Result<()>;
+    ///Creates `dir`, whose parent must exist, failing if something already exists at `dir`
+    fn create_dir(&self, dir: &VfsPath) -> Result<()>;
+    ///Removes `dir`, which must be empty
+    fn remove_dir(&self, dir: &VfsPath) -> Result<()>;
+    ///Removes `dir` and everything in it
+    fn remove_dir_all(&self, dir: &VfsPath) -> Result<()>;
+    ///Moves the file or directory `from` to `to`, replacing `to` if it is a file.
+    ///Always atomic, a move across filesystems fails with [std::io::ErrorKind::CrossesDevices] instead of copying.
+    fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()>;
+    fn remove_file(&self, file: &VfsPath) -> Result<()>;
+    ///Copies the file `from` to `to`, replacing `to` if it is a file, and returns the number of bytes copied
+    fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        copy_file(self, from, to)
+    }
+    fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata>;
+    ///True if `path` is a file or directory, false if it or one of its parents doesn't exist
+    fn exists(&self, path: &VfsPath) -> Result<bool> {
+        match self.metadata(path) {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e),
         }
     }
-    fn domain_file(&self, domain: &str) -> Result<PathBuf> {
+    ///Makes changes to the entries of `dir`, e.g. a rename into it, durable. A no-op for backends without durable storage
+    fn sync_dir(&self, _dir: &VfsPath) -> Result<()> {
+        Ok(())
+    }
+    ///Starts an [AtomicWriter] which will replace `target` in one step when committed
+    fn atomic_writer(&self, target: VfsPath) -> Result<AtomicWriter<'_, Self>> {
+        AtomicWriter::new(self, target)
+    }
+    ///Atomically replaces `target` with `data`, see [AtomicWriter]
+    fn write_atomic(&self, target: VfsPath, data: &[u8]) -> Result<()> {
+        let mut writer = self.atomic_writer(target)?;
+        writer.write_all(data).map_err(VfsErr::Io)?;
+        writer.commit()
+    }
+    fn domain_file(&self, domain: &str) -> Result<VfsPath> {
         self.resolve(format!("{}/{}", DOMAINS_SUBDIR, domain).as_str())
     }
-    fn resource_dir(&self, service_id: i64) -> Result<PathBuf> {
+    fn resource_dir(&self, service_id: i64) -> Result<VfsPath> {
         let dir = self.resolve(format!("{}/{}", service_id, RESOURCES_SUBDIR).as_str())?;
-        fs::create_dir_all(dir.clone()).map_err(VfsErr::Io)?;
+        if !self.lazy_dirs() {
+            self.create_dir_all(&dir)?;
+        }
         Ok(dir)
     }
-    fn plugins_dir(&self, service_id: i64) -> Result<PathBuf> {
+    fn plugins_dir(&self, service_id: i64) -> Result<VfsPath> {
         let dir = self.resolve(format!("{}/{}", service_id, PLUGINS_SUBDIR).as_str())?;
-        fs::create_dir_all(dir.clone()).map_err(VfsErr::Io)?;
+        if !self.lazy_dirs() {
+            self.create_dir_all(&dir)?;
+        }
         Ok(dir)
     }
-    fn tmp_dir(&self, service_id: i64) -> Result<PathBuf> {
+    fn tmp_dir(&self, service_id: i64) -> Result<VfsPath> {
         let dir = self.resolve(format!("{}/{}", service_id, TMP_SUBDIR).as_str())?;
-        fs::create_dir_all(dir.clone()).map_err(VfsErr::Io)?;
+        if !self.lazy_dirs() {
+            self.create_dir_all(&dir)?;
+        }
         Ok(dir)
     }
-    fn resource_file(&self, service_id: i64, name: &str) -> Result<PathBuf> {
-        let mut path = self.resource_dir(service_id)?;
-        path.push(name);
-        Ok(path)
+    ///A new path in the service's [TMP_SUBDIR] which no other caller will be given
+    fn tmp_file(&self, service_id: i64) -> Result<VfsPath> {
+        self.tmp_dir(service_id)?.join(unique_name("upload"))
     }
-    fn schema_file(&self, service_id: i64, is_draft: bool, version: &str, file: &str) -> Result<PathBuf> {
+    fn resource_file(&self, service_id: i64, name: &str) -> Result<VfsPath> {
+        self.resource_dir(service_id)?.join(name)
+    }
+    ///The directory of a draft, or of a published version when `is_draft` is false
+    fn version_dir(&self, service_id: i64, is_draft: bool, version: &str) -> Result<VfsPath> {
+        self.resolve(format!("{}/{}/{}", service_id, if is_draft { DRAFTS_SUBDIR } else { VERSIONS_SUBDIR }, version).as_str())
+    }
+    fn schema_file(&self, service_id: i64, is_draft: bool, version: &str, file: &str) -> Result<VfsPath> {
         self.resolve(format!("{}/{}/{}/{}", service_id, if is_draft { DRAFTS_SUBDIR } else { VERSIONS_SUBDIR }, version, file).as_str())
     }
-    fn ecma_dir(&self, service_id: i64, is_draft: bool, version: &str) -> Result<PathBuf> {
+    fn ecma_dir(&self, service_id: i64, is_draft: bool, version: &str) -> Result<VfsPath> {
         self.resolve(
             format!(
                 "{}/{}/{}/{}",
@@ -136,24 +560,109 @@ pub trait Vfs: Sync + Send {
                 .as_str(),
         )
     }
-    fn read(&self, file: PathBuf) -> Result<Box<dyn Read + '_>>;
-    fn open_with(&self, file: PathBuf, opts: OpenOptions) -> Result<Box<dyn VfsFile>>;
+    fn read(&self, file: VfsPath) -> Result<Box<dyn Read + '_>>;
+    ///The whole content of `file` in one buffer. Backends avoid copying where they can,
+    ///[MemoryVfs] hands out the stored bytes and [FilesystemVfs] memory-maps large files
+    fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        let mut data = vec![];
+        self.read(file)?.read_to_end(&mut data).map_err(VfsErr::Io)?;
+        Ok(Bytes::from(data))
+    }
+    fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn VfsFile>>;
+    ///The options of `domain`. If there's no file for `domain` itself, wildcard files are tried from the most to the least
+    ///specific, e.g. `*.shop.example.com` then `*.example.com`. If the file found is an alias, the domain it names is
+    ///looked up the same way. Fails with [VfsErr::Domain] if nothing matches or [VfsErr::DomainLoop] if aliases form a loop.
     fn read_domain_file(&self, domain: &str) -> Result<DomainOptions> {
-        match self.domain_file(domain) {
-            Ok(file) => {
-                let mut data = vec![];
-                let mut input = self.read(file)?;
-                let mut buffer = [0; 1024];
-                while let Ok(n) = input.read(&mut buffer).map_err(VfsErr::Io) {
-                    if n == 0 {
-                        break;
-                    }
-                    data.extend_from_slice(&buffer[0..n]);
-                }
-                Ok(serde_json::from_slice(&data).map_err(VfsErr::JsonErr)?)
+        crate::domain::resolve_domain(self, domain)
+    }
+    ///The content of the file for exactly `domain`, without following wildcards or aliases
+    fn read_domain_entry(&self, domain: &str) -> Result<DomainEntry> {
+        let data = self.read_bytes(self.domain_file(domain)?)?;
+        Ok(crate::domain::parse_domain_file(&data)?.entry)
+    }
+    ///Rewrites every domain file in an older format, see [DomainFile](crate::domain::DomainFile), in the current format and returns their domains
+    fn migrate_domain_files(&self) -> Result<Vec<String>> {
+        crate::domain::migrate_domain_files(self)
+    }
+    ///Atomically creates or replaces the domain file for `domain`.
+    ///Fails with [VfsErr::InvalidDomain] if `domain` isn't a valid hostname or [VfsErr::VersionNotFound] if the version doesn't exist.
+    fn write_domain_file(&self, domain: &str, options: &DomainOptions) -> Result<()> {
+        crate::domain::write_domain_file(self, domain, options)
+    }
+    ///Atomically makes `domain` an alias of `alias_of`, so it is served with the same options.
+    ///Fails if `alias_of` can't be resolved or the alias would create a loop.
+    fn write_domain_alias(&self, domain: &str, alias_of: &str) -> Result<()> {
+        crate::domain::write_domain_alias(self, domain, alias_of)
+    }
+    ///Deletes the domain file for `domain`, failing with [VfsErr::Domain] if there isn't one
+    fn delete_domain_file(&self, domain: &str) -> Result<()> {
+        crate::domain::delete_domain_file(self, domain)
+    }
+    ///Atomically points an existing domain at another version of the same service and returns its new options.
+    ///Fails with [VfsErr::DomainIsAlias] for an alias, rebind the domain it points at instead.
+    ///Fails with [VfsErr::Conflict] if the domain file is changed by someone else at the same time.
+    fn rebind_domain(&self, domain: &str, version: &str, is_draft: bool) -> Result<DomainOptions> {
+        crate::domain::rebind_domain(self, domain, version, is_draft)
+    }
+    ///The index of the domain files kept by this [Vfs], if it keeps one. Without it [Vfs::domains_for_service]
+    ///and [Vfs::domains_for_version] read every domain file on each call.
+    fn domain_index(&self) -> Option<&DomainIndex> {
+        None
+    }
+    ///Every domain serving any version of `service_id`, sorted
+    fn domains_for_service(&self, service_id: i64) -> Result<Vec<String>> {
+        crate::domain::domains_for_service(self, service_id)
+    }
+    ///Every domain serving the given draft or version of `service_id`, sorted
+    fn domains_for_version(&self, service_id: i64, version: &str, is_draft: bool) -> Result<Vec<String>> {
+        crate::domain::domains_for_version(self, service_id, version, is_draft)
+    }
+    ///Every domain file and its options. Files which can't be parsed are logged and skipped
+    fn read_domains(&self) -> Result<Vec<(String, DomainOptions)>> {
+        let dir = self.resolve(DOMAINS_SUBDIR)?;
+        let entries = match self.read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if is_not_found(&e) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut domains = vec![];
+        for path in entries {
+            if path.as_path().parent() != Some(dir.as_path()) || is_atomic_tmp(path.as_path()) {
+                continue;
+            }
+            let domain = match path.file_name().and_then(|name| name.to_str()) {
+                Some(domain) => domain.to_string(),
+                None => continue,
+            };
+            match self.read_domain_file(&domain) {
+                Ok(options) => domains.push((domain, options)),
+                Err(e) => warn!("Skipping domain file {} - {}", path.as_path().to_string_lossy(), e),
             }
-            Err(e) => Err(e),
         }
+        Ok(domains)
+    }
+    ///Every draft and published version of `service_id`, drafts first and then sorted by name
+    fn list_versions(&self, service_id: i64) -> Result<Vec<VersionInfo>> {
+        crate::version::list_versions(self, service_id)
+    }
+    ///Deletes a draft, or a published version if `is_draft` is false, and all of its files.
+    ///Fails with [VfsErr::VersionInUse] while any domain file still points at it, [VfsErr::VersionNotFound] if it doesn't exist.
+    fn delete_version(&self, service_id: i64, is_draft: bool, version: &str) -> Result<()> {
+        crate::version::delete_version(self, service_id, is_draft, version)
+    }
+    ///What changed going from `a` to `b`, with a unified diff of each changed schema or ECMA script.
+    ///Fails with [VfsErr::VersionNotFound] if either doesn't exist.
+    fn diff_versions(&self, service_id: i64, a: &VersionRef, b: &VersionRef) -> Result<VersionDiff> {
+        crate::diff::diff_versions(self, service_id, a, b)
+    }
+    ///Publishes the draft `draft` of `service_id` as the new version `new_version` with the default [PublishOptions]
+    fn publish_draft(&self, service_id: i64, draft: &str, new_version: &str) -> Result<PublishedVersion> {
+        self.publish_draft_with(service_id, draft, new_version, &PublishOptions::default())
+    }
+    ///Copies the draft `draft` of `service_id` to the new version `new_version`, failing with [VfsErr::VersionExists]
+    ///if it already exists. Versions are never written to again once published.
+    fn publish_draft_with(&self, service_id: i64, draft: &str, new_version: &str, opts: &PublishOptions) -> Result<PublishedVersion> {
+        crate::version::publish_draft(self, service_id, draft, new_version, opts)
     }
     fn read_resource_file(&self, service_id: i64, filename: &str) -> Result<Box<dyn Read + '_>> {
         match self.resource_file(service_id, filename) {
@@ -161,119 +670,157 @@ pub trait Vfs: Sync + Send {
             Err(e) => Err(e),
         }
     }
+    ///The whole content of a resource, see [Vfs::read_bytes]
+    fn read_resource_bytes(&self, service_id: i64, filename: &str) -> Result<Bytes> {
+        self.read_bytes(self.resource_file(service_id, filename)?)
+    }
     fn read_schema_file(&self, service_id: i64, is_draft: bool, version: &str, filename: &str) -> Result<String> {
-        match self.schema_file(service_id, is_draft, version, filename) {
-            Ok(file) => {
-                let mut data = vec![];
-                let mut input = self.read(file)?;
-                let mut buffer = [0; 1024];
-                while let Ok(n) = input.read(&mut buffer).map_err(VfsErr::Io) {
-                    if n == 0 {
-                        break;
-                    }
-                    data.extend_from_slice(&buffer[0..n]);
-                }
-                Ok(String::from_utf8(data).map_err(VfsErr::Utf8)?)
-            }
-            Err(e) => Err(e),
-        }
+        let data = self.read_bytes(self.schema_file(service_id, is_draft, version, filename)?)?;
+        String::from_utf8(data.into()).map_err(VfsErr::Utf8)
+    }
+    ///`schema.xml` and every file it imports, directly or not, keyed by file name.
+    ///Files are in the order they're found, a depth first walk of the imports in document order starting with `schema.xml`,
+    ///and a file imported more than once appears once. Fails with [VfsErr::MissingImport] or [VfsErr::ImportCycle].
+    fn load_schema_bundle(&self, service_id: i64, is_draft: bool, version: &str) -> Result<IndexMap<String, String>> {
+        crate::schema::load_schema_bundle(self, service_id, is_draft, version)
+    }
+    ///The size and hash of every script in the version's [ECMA_SUBDIR].
+    ///A draft's manifest is built every time, a published version's is read from its
+    ///[ECMA_MANIFEST_FILE](crate::ecma::ECMA_MANIFEST_FILE) which is written when the version is published.
+    ///Versions published before manifests existed have theirs built every time, nothing is written to them.
+    fn ecma_manifest(&self, service_id: i64, is_draft: bool, version: &str) -> Result<EcmaManifest> {
+        crate::ecma::ecma_manifest(self, service_id, is_draft, version)
+    }
+    ///Starts watching the schema and ECMA files of a draft or version and the service's resources and plugins.
+    ///Changes are debounced and grouped by [WatchKind](crate::watch::WatchKind), see [Watcher].
+    ///Backends which can't be watched fail with [std::io::ErrorKind::Unsupported].
+    fn watch(&self, _service_id: i64, _is_draft: bool, _version: &str) -> Result<Watcher> {
+        Err(VfsErr::Io(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "This Vfs can't watch for changes",
+        )))
     }
     fn read_ecma<'a>(&'a self, service_id: i64, is_draft: bool, version: &str) -> Result<DirStream<'a, Self>> {
         let dir = self.ecma_dir(service_id, is_draft, version)?;
         self.dir_stream(dir)
     }
-    fn dir_stream<'a>(&'a self, dir: PathBuf) -> Result<DirStream<'a, Self>> {
-        if dir.to_string_lossy().contains("..") {
-            warn!("ECMA script path cannot contain '..' i.e. must be absolute, full path");
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "ECMA script path can't have .. in {}",
-                dir.to_string_lossy()
-            )));
-        }
-        match self.read_dir(&dir) {
-            Ok(read_dir) => {
-                let mut stream: DirStream<'a, Self> = DirStream {
-                    base: dir,
-                    buf: VecDeque::new(),
-                    vfs: self,
-                };
-                stream.buf.push_back(read_dir);
-                Ok(stream)
-            }
-            Err(e) => Err(e),
+    ///Every file under `dir` with its metadata, sorted and depth first, see [DirStream]
+    fn dir_stream<'a>(&'a self, dir: VfsPath) -> Result<DirStream<'a, Self>> {
+        if let Err(e) = self.check_path(dir.as_path()) {
+            warn!("Path must be under the root, got {}", dir.as_path().to_string_lossy());
+            return Err(e);
         }
+        let entries = self.read_dir(&dir)?;
+        let mut stream = DirStream {
+            base: dir,
+            stack: vec![],
+            max_depth: None,
+            vfs: self,
+        };
+        stream.push(entries);
+        Ok(stream)
     }
-    fn read_dir(&self, dir: &PathBuf) -> Result<VirtualReadDir>;
+    fn read_dir(&self, dir: &VfsPath) -> Result<VirtualReadDir>;
 }
 
 pub struct VirtualReadDir {
-    inner: Box<dyn Iterator<Item=PathBuf>>,
+    inner: Box<dyn Iterator<Item=VfsPath>>,
+}
+
+impl VirtualReadDir {
+    ///Wraps the entries a backend listed in `dir`, which are therefore under the root too.
+    pub(crate) fn new<I>(dir: &VfsPath, entries: I) -> Self
+        where
+            I: Iterator<Item=PathBuf> + 'static,
+    {
+        let service_id = dir.service_id();
+        VirtualReadDir {
+            inner: Box::new(entries.map(move |path| VfsPath::new(path, service_id))),
+        }
+    }
 }
 
 impl Iterator for VirtualReadDir {
-    type Item = PathBuf;
+    type Item = VfsPath;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.inner.next()
     }
 }
 
+///A file found by a [DirStream]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    ///The path of the file relative to the directory being walked
+    pub rel: PathBuf,
+    pub path: VfsPath,
+    pub metadata: VfsMetadata,
+}
+
+///Every file under a directory, see [Vfs::dir_stream]. The walk is depth first and the entries of each directory are
+///visited in name order, so the files in a sub-directory come where the sub-directory sorts among its siblings and
+///every backend returns the same files in the same order. Directories themselves aren't returned.
 pub struct DirStream<'a, F>
     where
         F: Vfs + ?Sized,
 {
-    base: PathBuf,
-    buf: VecDeque<VirtualReadDir>,
+    base: VfsPath,
+    ///The entries not visited yet of each directory being walked, the deepest last
+    stack: Vec<std::vec::IntoIter<VfsPath>>,
+    max_depth: Option<usize>,
     vfs: &'a F,
 }
 
-impl<'a, F: Vfs> Iterator for DirStream<'a, F> {
-    type Item = Result<(PathBuf, PathBuf)>;
+impl<'a, F: Vfs + ?Sized> DirStream<'a, F> {
+    ///Descends at most `depth` directories below the one being walked, 0 only returns the files directly in it
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    fn push(&mut self, entries: VirtualReadDir) {
+        let mut entries: Vec<_> = entries.collect();
+        entries.sort();
+        self.stack.push(entries.into_iter());
+    }
+}
+
+impl<'a, F: Vfs + ?Sized> Iterator for DirStream<'a, F> {
+    type Item = Result<DirEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(dir) = self.buf.back_mut() {
-            if let Some(path) = dir.next() {
-                //can't use canonicalize because it goes to the filesystem
-                // let path = match path.canonicalize().map_err(VfsErr::Io) {
-                //     Ok(p) => p,
-                //     Err(e) => return Some(Err(e)),
-                // };
-                if path.to_string_lossy().contains("..") {
-                    warn!(
-                        "Skipping path {} because it contains '..'",
-                        path.to_string_lossy()
-                    );
-                    return self.next();
-                }
-                if path.is_dir() {
-                    match self.vfs.read_dir(&path) {
-                        Ok(child) => {
-                            self.buf.push_front(child);
-                            self.next()
-                        }
-                        Err(e) => Some(Err(e)),
-                    }
-                } else {
-                    if path.starts_with(&self.base) {
-                        let filename = match path
-                            .strip_prefix(&self.base)
-                            .map_err(VfsErr::StripPrefixErr)
-                        {
-                            Ok(p) => p,
-                            Err(e) => return Some(Err(e)),
-                        };
-                        Some(Ok((filename.to_owned(), path)))
-                    } else {
-                        //Some(Err(VfsErr::Generic(format!())))
-                        self.next() //silently skip files that are not in the service's base directory
-                    }
-                }
-            } else {
-                self.buf.pop_back();
-                self.next()
+        loop {
+            let depth = self.stack.len();
+            let Some(path) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if self.vfs.check_path(path.as_path()).is_err() {
+                warn!(
+                    "Skipping path {} because it is not under the root",
+                    path.as_path().to_string_lossy()
+                );
+                continue;
+            }
+            //silently skip files that are not in the base directory and files being written by an AtomicWriter
+            let rel = match path.as_path().strip_prefix(self.base.as_path()) {
+                Ok(rel) if !rel.as_os_str().is_empty() && !is_atomic_tmp(path.as_path()) => rel.to_path_buf(),
+                _ => continue,
+            };
+            let metadata = match self.vfs.metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => return Some(Err(e)),
+            };
+            if !metadata.is_dir() {
+                return Some(Ok(DirEntry { rel, path, metadata }));
+            }
+            if self.max_depth.is_some_and(|max| depth > max) {
+                continue;
+            }
+            match self.vfs.read_dir(&path) {
+                Ok(entries) => self.push(entries),
+                Err(e) => return Some(Err(e)),
             }
-        } else {
-            None
         }
     }
 }
@@ -283,15 +830,28 @@ pub struct FilesystemVfs {
     ///The absolute path to the directory where the services are kept
     ///This is important because we ensure that all operations are a sub-directory of this
     services_dir: PathBuf,
+    ///When true every path is opened relative to a handle on [services_dir] and symlinks are never followed.
+    ///See [FilesystemVfs::hardened]
+    hardened: bool,
+    ///See [Vfs::lazy_dirs]
+    lazy_dirs: bool,
+    ///See [FilesystemVfs::with_mmap_threshold]
+    mmap_threshold: u64,
+    ///Shared by clones, see [Vfs::domain_index]
+    domains: Arc<DomainIndex>,
 }
 
-pub trait VfsFile: Read + Write + Seek {
-    fn path(&self) -> PathBuf;
+pub trait VfsFile: Read + Write + Seek + Send {
+    fn path(&self) -> VfsPath;
     fn clone(&self) -> Result<Box<dyn VfsFile>>;
+    ///Flushes the file and, for backends with durable storage, waits until its content has reached it
+    fn sync_all(&mut self) -> Result<()> {
+        self.flush().map_err(VfsErr::Io)
+    }
 }
 
 impl dyn VfsFile {
-    pub fn save_to<F>(&self, fs: Arc<BoundVfs<F>>, new_name: Option<String>) -> Result<String>
+    pub fn save_to<F>(&mut self, fs: Arc<BoundVfs<F>>, new_name: Option<String>) -> Result<String>
         where
             F: Vfs,
     {
@@ -311,19 +871,26 @@ impl Debug for dyn VfsFile {
     }
 }
 
-pub struct VfsFileSystemFile(File, PathBuf);
+///A file opened by [FilesystemVfs]. The last field is the services root if the file was opened in hardened mode,
+///in which case clones are opened the same way.
+pub struct VfsFileSystemFile(File, VfsPath, Option<PathBuf>);
 
 impl VfsFile for VfsFileSystemFile {
-    fn path(&self) -> PathBuf {
+    fn path(&self) -> VfsPath {
         self.1.clone()
     }
     fn clone(&self) -> Result<Box<dyn VfsFile>> {
-        let mut opts = OpenOptions::new();
+        let mut opts = VfsOpenOptions::new();
         opts.read(true);
-        Ok(Box::new(VfsFileSystemFile(
-            opts.open(self.1.clone()).map_err(VfsErr::Io)?,
-            self.1.clone(),
-        )))
+        let file = match &self.2 {
+            Some(root) => open_beneath(root, self.1.as_path(), &opts)?,
+            None => opts.to_std().open(self.1.as_path()).map_err(VfsErr::Io)?,
+        };
+        Ok(Box::new(VfsFileSystemFile(file, self.1.clone(), self.2.clone())))
+    }
+    fn sync_all(&mut self) -> Result<()> {
+        self.0.flush().map_err(VfsErr::Io)?;
+        self.0.sync_all().map_err(VfsErr::Io)
     }
 }
 
@@ -354,71 +921,473 @@ impl Vfs for FilesystemVfs {
         &self.services_dir
     }
 
-    fn read(&self, file: PathBuf) -> Result<Box<dyn Read + '_>> {
-        if file.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot read file with .. in path {}",
-                file.to_string_lossy()
-            )));
+    fn read(&self, file: VfsPath) -> Result<Box<dyn Read + '_>> {
+        self.check_path(file.as_path())?;
+        if self.hardened {
+            let mut opts = VfsOpenOptions::new();
+            opts.read(true);
+            return Ok(Box::new(open_beneath(&self.services_dir, file.as_path(), &opts)?));
         }
         Ok(Box::new(File::open(file).map_err(VfsErr::Io)?))
     }
-    fn open_with(&self, path: PathBuf, opts: OpenOptions) -> Result<Box<dyn VfsFile>> {
-        if path.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot open file with .. in path {}",
-                path.to_string_lossy()
-            )));
+
+    fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        let mut opts = VfsOpenOptions::new();
+        opts.read(true);
+        let mut input = self.open_file(&file, &opts)?;
+        let len = input.metadata().map_err(VfsErr::Io)?.len();
+        if len > 0 && len >= self.mmap_threshold {
+            //SAFETY: the map is only valid while the file isn't truncated. Mapping is opt-in and with_mmap_threshold
+            //requires that files are only replaced with a rename, e.g. by an AtomicWriter, which leaves a mapped file untouched
+            let map = unsafe { memmap2::Mmap::map(&input) }.map_err(VfsErr::Io)?;
+            return Ok(Bytes::from_owner(map));
         }
-        let file = opts.open(path.clone()).map_err(VfsErr::Io)?;
-        Ok(Box::new(VfsFileSystemFile(file, path)))
+        let mut data = Vec::with_capacity(len as usize);
+        input.read_to_end(&mut data).map_err(VfsErr::Io)?;
+        Ok(Bytes::from(data))
+    }
+    fn open_with(&self, path: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn VfsFile>> {
+        let file = self.open_file(&path, &opts)?;
+        let root = if self.hardened { Some(self.services_dir.clone()) } else { None };
+        Ok(Box::new(VfsFileSystemFile(file, path, root)))
     }
 
-    fn read_dir(&self, dir: &PathBuf) -> Result<VirtualReadDir> {
-        if dir.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot read dir with .. in path {}",
-                dir.to_string_lossy()
-            )));
+    fn read_dir(&self, dir: &VfsPath) -> Result<VirtualReadDir> {
+        self.check_path(dir.as_path())?;
+        if self.hardened {
+            let it = read_dir_beneath(&self.services_dir, dir.as_path())?;
+            return Ok(VirtualReadDir::new(dir, it.into_iter()));
         }
         let it = fs::read_dir(dir).map_err(VfsErr::Io)?;
-        let it = it.map(|v| v.map(|e| e.path())).flatten();
-        let it: Box<dyn Iterator<Item=PathBuf>> = Box::new(it);
-        Ok(VirtualReadDir { inner: it })
+        let it = it.flat_map(|v| v.map(|e| e.path()));
+        Ok(VirtualReadDir::new(dir, it))
+    }
+
+    fn lazy_dirs(&self) -> bool {
+        self.lazy_dirs
+    }
+
+    fn domain_index(&self) -> Option<&DomainIndex> {
+        Some(&self.domains)
+    }
+
+    fn create_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        if self.hardened {
+            return create_dir_all_beneath(&self.services_dir, dir.as_path());
+        }
+        fs::create_dir_all(dir).map_err(VfsErr::Io)
+    }
+
+    fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        if self.hardened {
+            return create_dir_beneath(&self.services_dir, dir.as_path());
+        }
+        fs::create_dir(dir).map_err(VfsErr::Io)
+    }
+
+    fn remove_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        if self.hardened {
+            return remove_beneath(&self.services_dir, dir.as_path(), true);
+        }
+        fs::remove_dir(dir).map_err(VfsErr::Io)
+    }
+
+    fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        if self.hardened {
+            return remove_dir_all_beneath(&self.services_dir, dir.as_path());
+        }
+        fs::remove_dir_all(dir).map_err(VfsErr::Io)
+    }
+
+    fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata> {
+        self.check_path(path.as_path())?;
+        if self.hardened {
+            return Ok(metadata_beneath(&self.services_dir, path.as_path())?.into());
+        }
+        Ok(fs::metadata(path).map_err(VfsErr::Io)?.into())
+    }
+
+    fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        self.check_path(from.as_path())?;
+        self.check_path(to.as_path())?;
+        if self.lazy_dirs {
+            if let Some(parent) = to.parent_unchecked() {
+                self.create_dir_all(&parent)?;
+            }
+        }
+        if self.hardened {
+            return rename_beneath(&self.services_dir, from.as_path(), to.as_path());
+        }
+        fs::rename(from, to).map_err(VfsErr::Io)
+    }
+
+    fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        self.check_path(file.as_path())?;
+        if self.hardened {
+            return remove_beneath(&self.services_dir, file.as_path(), false);
+        }
+        fs::remove_file(file).map_err(VfsErr::Io)
+    }
+
+    fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        if self.hardened {
+            //fs::copy would follow symlinks, copy through open_with instead
+            return copy_file(self, from, to);
+        }
+        self.check_path(from.as_path())?;
+        self.check_path(to.as_path())?;
+        if self.lazy_dirs {
+            if let Some(parent) = to.parent_unchecked() {
+                self.create_dir_all(&parent)?;
+            }
+        }
+        fs::copy(from, to).map_err(VfsErr::Io)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(&self, service_id: i64, is_draft: bool, version: &str) -> Result<Watcher> {
+        let targets = WatchTargets::new(self, service_id, is_draft, version)?;
+        crate::watch::inotify::watch(&self.services_dir, self.hardened, targets)
+    }
+
+    fn sync_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        let dir = if self.hardened {
+            open_dir_beneath(&self.services_dir, dir.as_path())?
+        } else {
+            File::open(dir).map_err(VfsErr::Io)?
+        };
+        dir.sync_all().map_err(VfsErr::Io)
     }
 }
 
+///Copies the file `from` to `to` by reading it through `vfs` and writing it with [Vfs::open_with], see [Vfs::copy]
+fn copy_file<F: Vfs + ?Sized>(vfs: &F, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+    let mut input = vfs.read(from.clone())?;
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    let mut output = vfs.open_with(to.clone(), opts)?;
+    let copied = std::io::copy(&mut input, &mut output).map_err(VfsErr::Io)?;
+    output.flush().map_err(VfsErr::Io)?;
+    Ok(copied)
+}
+
+///Moves a file by copying it through `vfs` and removing the original, for when a rename isn't possible
+fn copy_then_remove<F: Vfs + ?Sized>(vfs: &F, from: &VfsPath, to: &VfsPath) -> Result<()> {
+    copy_file(vfs, from, to)?;
+    vfs.remove_file(from)
+}
+
 impl FilesystemVfs {
     pub fn new(services_dir: String) -> Self {
         FilesystemVfs {
             services_dir: PathBuf::from(services_dir),
+            hardened: false,
+            lazy_dirs: false,
+            mmap_threshold: u64::MAX,
+            domains: Arc::new(DomainIndex::new()),
+        }
+    }
+    ///Creates a [FilesystemVfs] which refuses to follow symlinks anywhere under `services_dir`.
+    ///Files are opened relative to a handle on `services_dir` using `openat2(RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS)`,
+    ///or an `O_NOFOLLOW` walk of each path component on kernels without `openat2`.
+    ///A path that would leave the root this way fails with [VfsErr::PathEscapesRoot].
+    ///Hardened mode needs `O_PATH`, so it is only available on Linux, elsewhere every access fails with [std::io::ErrorKind::Unsupported].
+    pub fn hardened(services_dir: String) -> Self {
+        FilesystemVfs {
+            services_dir: PathBuf::from(services_dir),
+            hardened: true,
+            lazy_dirs: false,
+            mmap_threshold: u64::MAX,
+            domains: Arc::new(DomainIndex::new()),
+        }
+    }
+    pub fn is_hardened(&self) -> bool {
+        self.hardened
+    }
+    ///Opens `path` as [Vfs::open_with] does, creating its parent first with [Vfs::lazy_dirs]
+    pub(crate) fn open_file(&self, path: &VfsPath, opts: &VfsOpenOptions) -> Result<File> {
+        self.check_path(path.as_path())?;
+        if self.lazy_dirs && (opts.is_create() || opts.is_create_new()) {
+            if let Some(parent) = path.parent_unchecked() {
+                self.create_dir_all(&parent)?;
+            }
+        }
+        if self.hardened {
+            return open_beneath(&self.services_dir, path.as_path(), opts);
+        }
+        opts.to_std().open(path.as_path()).map_err(VfsErr::Io)
+    }
+    ///Turns on [Vfs::lazy_dirs] so service directories are only created on the first write into them
+    pub fn with_lazy_dirs(mut self, lazy_dirs: bool) -> Self {
+        self.lazy_dirs = lazy_dirs;
+        self
+    }
+    ///Memory-maps files of at least `threshold` bytes in [Vfs::read_bytes] instead of reading them, [MMAP_THRESHOLD]
+    ///is a good value. Off, i.e. [u64::MAX], by default because truncating a file while a map of it is in use crashes
+    ///the whole process: only turn it on if nothing writes files under the root in place, e.g. with [BoundVfs::open],
+    ///[Vfs::copy] or another process, and every change goes through an [AtomicWriter] or publishing instead.
+    pub fn with_mmap_threshold(mut self, threshold: u64) -> Self {
+        self.mmap_threshold = threshold;
+        self
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_beneath(root: &Path, path: &Path, opts: &VfsOpenOptions) -> Result<File> {
+    let rel = path.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::open(root, rel, opts.oflags())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_dir_beneath(root: &Path, dir: &Path) -> Result<File> {
+    use rustix::fs::OFlags;
+    let rel = dir.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::open(root, rel, OFlags::RDONLY | OFlags::DIRECTORY)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn read_dir_beneath(root: &Path, dir: &Path) -> Result<Vec<PathBuf>> {
+    let rel = dir.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::read_dir(root, rel)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn create_dir_all_beneath(root: &Path, dir: &Path) -> Result<()> {
+    let rel = dir.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::create_dir_all(root, rel)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn create_dir_beneath(root: &Path, dir: &Path) -> Result<()> {
+    let rel = dir.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::create_dir(root, rel)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn remove_beneath(root: &Path, path: &Path, is_dir: bool) -> Result<()> {
+    let rel = path.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::remove(root, rel, is_dir)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn remove_dir_all_beneath(root: &Path, dir: &Path) -> Result<()> {
+    let rel = dir.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::remove_dir_all(root, rel)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn metadata_beneath(root: &Path, path: &Path) -> Result<fs::Metadata> {
+    let rel = path.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::metadata(root, rel)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn rename_beneath(root: &Path, from: &Path, to: &Path) -> Result<()> {
+    let from = from.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    let to = to.strip_prefix(root).map_err(VfsErr::StripPrefixErr)?;
+    crate::beneath::rename(root, from, to)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn hardened_unsupported(path: &Path) -> VfsErr {
+    VfsErr::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("Hardened mode needs O_PATH and is only supported on Linux, can't access {}", path.to_string_lossy()),
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn open_beneath(_root: &Path, path: &Path, _opts: &VfsOpenOptions) -> Result<File> {
+    Err(hardened_unsupported(path))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn open_dir_beneath(_root: &Path, dir: &Path) -> Result<File> {
+    Err(hardened_unsupported(dir))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn read_dir_beneath(_root: &Path, dir: &Path) -> Result<Vec<PathBuf>> {
+    Err(hardened_unsupported(dir))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn create_dir_all_beneath(_root: &Path, dir: &Path) -> Result<()> {
+    Err(hardened_unsupported(dir))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn create_dir_beneath(_root: &Path, dir: &Path) -> Result<()> {
+    Err(hardened_unsupported(dir))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn rename_beneath(_root: &Path, from: &Path, _to: &Path) -> Result<()> {
+    Err(hardened_unsupported(from))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn remove_beneath(_root: &Path, path: &Path, _is_dir: bool) -> Result<()> {
+    Err(hardened_unsupported(path))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn remove_dir_all_beneath(_root: &Path, dir: &Path) -> Result<()> {
+    Err(hardened_unsupported(dir))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn metadata_beneath(_root: &Path, path: &Path) -> Result<fs::Metadata> {
+    Err(hardened_unsupported(path))
+}
+
+///The contents of a file in a [MemoryVfs]. Like an inode, open files keep a handle on it so they
+///follow it through renames and their writes are lost if it's removed.
+struct MemNode {
+    ///The content and when it was last changed
+    data: RwLock<(Bytes, SystemTime)>,
+    created: SystemTime,
+}
+
+impl MemNode {
+    fn new(data: Bytes) -> Arc<Self> {
+        let now = SystemTime::now();
+        Arc::new(MemNode {
+            data: RwLock::new((data, now)),
+            created: now,
+        })
+    }
+    fn bytes(&self) -> std::io::Result<Bytes> {
+        Ok(self.data.read().map_err(|_| poisoned())?.0.clone())
+    }
+    fn set(&self, data: Bytes) -> std::io::Result<()> {
+        *self.data.write().map_err(|_| poisoned())? = (data, SystemTime::now());
+        Ok(())
+    }
+    fn metadata(&self) -> std::io::Result<VfsMetadata> {
+        let data = self.data.read().map_err(|_| poisoned())?;
+        Ok(VfsMetadata {
+            len: data.0.len() as u64,
+            kind: VfsFileKind::File,
+            readonly: false,
+            modified: Some(data.1),
+            created: Some(self.created),
+        })
+    }
+}
+
+///State shared by a [MemoryVfs] and all of its clones and open files
+#[derive(Default)]
+struct MemState {
+    files: BTreeMap<PathBuf, Arc<MemNode>>,
+    ///Directories created explicitly, any parent of a file is a directory too
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemState {
+    fn is_dir(&self, root: &Path, path: &Path) -> bool {
+        path == root || self.dirs.contains(path) || self.has_children(path)
+    }
+    fn create_dir_all(&mut self, root: &Path, dir: &Path) {
+        for ancestor in dir.ancestors().take_while(|p| *p != root && p.starts_with(root)) {
+            self.dirs.insert(ancestor.to_path_buf());
         }
     }
+    ///Moves the file or directory tree at `from` to `to`
+    fn rename(&mut self, root: &Path, from: &Path, to: &Path) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+        if let Some(node) = self.files.get(from).cloned() {
+            if self.is_dir(root, to) {
+                return Err(Error::new(ErrorKind::IsADirectory, to.to_string_lossy()));
+            }
+            self.files.remove(from);
+            self.files.insert(to.to_path_buf(), node);
+            return Ok(());
+        }
+        if from == root || !self.is_dir(root, from) {
+            return Err(Error::new(ErrorKind::NotFound, from.to_string_lossy()));
+        }
+        if to.starts_with(from) {
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot move a directory into itself"));
+        }
+        if self.files.contains_key(to) {
+            return Err(Error::new(ErrorKind::NotADirectory, to.to_string_lossy()));
+        }
+        if self.has_children(to) {
+            return Err(Error::new(ErrorKind::DirectoryNotEmpty, to.to_string_lossy()));
+        }
+        let moved = |path: &Path| to.join(path.strip_prefix(from).unwrap_or(path));
+        let files: Vec<_> = self.files.keys().filter(|p| p.starts_with(from)).cloned().collect();
+        for path in files {
+            if let Some(node) = self.files.remove(&path) {
+                self.files.insert(moved(&path), node);
+            }
+        }
+        let dirs: Vec<_> = self.dirs.iter().filter(|p| p.starts_with(from)).cloned().collect();
+        for path in dirs {
+            self.dirs.remove(&path);
+            self.dirs.insert(moved(&path));
+        }
+        self.dirs.insert(to.to_path_buf());
+        Ok(())
+    }
+    fn has_children(&self, path: &Path) -> bool {
+        //paths are ordered by component so every descendant of path sorts right after it
+        let after = (Bound::Excluded(path), Bound::Unbounded);
+        self.files.range::<Path, _>(after).next().is_some_and(|(p, _)| p.starts_with(path))
+            || self.dirs.range::<Path, _>(after).next().is_some_and(|p| p.starts_with(path))
+    }
 }
 
-#[allow(unused)]
+///A file opened from a [MemoryVfs]. Writes are buffered in the file and only become visible to the [MemoryVfs]
+///when the file is flushed or dropped.
 pub struct MemVfsFile {
-    path: PathBuf,
+    path: VfsPath,
+    node: Arc<MemNode>,
+    notifier: Notifier,
     data: Vec<u8>,
     offset: usize,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    dirty: bool,
 }
 
 impl Seek for MemVfsFile {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        match pos {
-            SeekFrom::Start(_start) => {}
-            SeekFrom::End(_end) => {}
-            SeekFrom::Current(_current) => {}
+        let offset = match pos {
+            SeekFrom::Start(start) => Some(start),
+            SeekFrom::End(end) => (self.data.len() as u64).checked_add_signed(end),
+            SeekFrom::Current(current) => (self.offset as u64).checked_add_signed(current),
+        };
+        match offset.and_then(|offset| usize::try_from(offset).ok()) {
+            Some(offset) => {
+                self.offset = offset;
+                Ok(offset as u64)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
         }
-        todo!();
-        // Ok(0)
     }
 }
 
 impl Read for MemVfsFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.readable {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "file was not opened for reading",
+            ));
+        }
         let start = self.offset;
-        let mut end = start + buf.len();
+        let mut end = start.saturating_add(buf.len());
         let buf_len = self.data.len();
         if end >= buf_len {
             end = buf_len;
@@ -436,36 +1405,113 @@ impl Read for MemVfsFile {
 
 impl Write for MemVfsFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.data.put_slice(buf);
+        if !self.writable {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "file was not opened for writing",
+            ));
+        }
+        if self.append {
+            self.offset = self.data.len();
+        }
+        let end = self.offset.checked_add(buf.len()).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "write past the largest possible file size")
+        })?;
+        if self.data.len() < end {
+            //writing past the end leaves a hole of zeros, like a sparse file
+            self.data
+                .try_reserve(end - self.data.len())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            self.data.resize(end, 0);
+        }
+        self.data[self.offset..end].copy_from_slice(buf);
+        self.offset = end;
+        self.dirty = true;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        println!(
-            "MemVfsFile::flush:{}",
-            String::from_utf8(self.data.clone()).unwrap()
-        );
+        if self.dirty {
+            self.node.set(Bytes::copy_from_slice(&self.data))?;
+            self.dirty = false;
+            self.notifier.notify(self.path.as_path());
+        }
         Ok(())
     }
 }
 
+impl Drop for MemVfsFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush {} on drop - {}", self.path.as_path().to_string_lossy(), e);
+        }
+    }
+}
+
 impl VfsFile for MemVfsFile {
-    fn path(&self) -> PathBuf {
+    fn path(&self) -> VfsPath {
         self.path.clone()
     }
     fn clone(&self) -> Result<Box<dyn VfsFile>> {
+        //like FilesystemVfs, a clone is a new read only handle positioned at the start
         Ok(Box::new(MemVfsFile {
             path: self.path.clone(),
+            node: self.node.clone(),
+            notifier: self.notifier.clone(),
             data: self.data.clone(),
             offset: 0,
+            readable: true,
+            writable: false,
+            append: false,
+            dirty: false,
         }))
     }
 }
 
+fn poisoned() -> std::io::Error {
+    std::io::Error::other("MemoryVfs lock poisoned")
+}
+
+///An in memory [Vfs], mostly useful in tests. Clones share the same files.
 #[derive(Clone)]
 pub struct MemoryVfs {
-    pub root: PathBuf,
-    pub data: HashMap<String, String>,
+    root: PathBuf,
+    state: Arc<RwLock<MemState>>,
+    ///See [Vfs::lazy_dirs]
+    lazy_dirs: bool,
+    ///Shared by clones, see [Vfs::domain_index]
+    domains: Arc<DomainIndex>,
+    ///Shared by clones and open files, see [Vfs::watch]
+    notifier: Notifier,
+}
+
+impl MemoryVfs {
+    ///Creates an empty [MemoryVfs]. `root` cannot be empty, all paths must start with it
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        MemoryVfs {
+            root: root.into(),
+            state: Arc::new(RwLock::new(MemState::default())),
+            lazy_dirs: false,
+            domains: Arc::new(DomainIndex::new()),
+            notifier: Notifier::default(),
+        }
+    }
+    ///Turns on [Vfs::lazy_dirs] so service directories are only created on the first write into them
+    pub fn with_lazy_dirs(mut self, lazy_dirs: bool) -> Self {
+        self.lazy_dirs = lazy_dirs;
+        self
+    }
+    ///Stores `data` at `path`, which is [Vfs::resolve]d against the root, replacing any existing file.
+    pub fn insert<D: Into<Bytes>>(&self, path: &str, data: D) -> Result<VfsPath> {
+        let path = self.resolve(path)?;
+        self.state
+            .write()
+            .map_err(|_| VfsErr::Io(poisoned()))?
+            .files
+            .insert(path.as_path().to_path_buf(), MemNode::new(data.into()));
+        self.notifier.notify(path.as_path());
+        Ok(path)
+    }
 }
 
 impl Vfs for MemoryVfs {
@@ -473,72 +1519,301 @@ impl Vfs for MemoryVfs {
         &self.root
     }
 
-    fn read(&self, file: PathBuf) -> Result<Box<dyn Read + '_>> {
-        if file.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot read file with .. in path {}",
-                file.to_string_lossy()
-            )));
-        }
-        match self.data.get(file.to_string_lossy().as_ref()) {
-            Some(data) => {
-                let data: &[u8] = data.as_bytes();
-                Ok(Box::new(data))
-            }
+    fn read(&self, file: VfsPath) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(self.read_bytes(file)?.reader()))
+    }
+
+    fn read_bytes(&self, file: VfsPath) -> Result<Bytes> {
+        self.check_path(file.as_path())?;
+        let state = self.state.read().map_err(|_| VfsErr::Io(poisoned()))?;
+        match state.files.get(file.as_path()) {
+            Some(node) => node.bytes().map_err(VfsErr::Io),
             None => Err(VfsErr::FileNotFound(format!(
                 "File not found - {}",
-                file.to_string_lossy()
+                file.as_path().to_string_lossy()
             ))),
         }
     }
 
-    fn open_with(&self, file: PathBuf, _opts: OpenOptions) -> Result<Box<dyn VfsFile>> {
-        if file.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot read file with .. in path {}",
-                file.to_string_lossy()
+    fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> Result<Box<dyn VfsFile>> {
+        self.check_path(file.as_path())?;
+        let writable = opts.is_write() || opts.is_append();
+        //the same combinations std::fs::OpenOptions rejects
+        let invalid = match (opts.is_write(), opts.is_append()) {
+            (_, true) => opts.is_truncate() && !opts.is_create_new(),
+            (true, false) => false,
+            (false, false) => opts.is_truncate() || opts.is_create() || opts.is_create_new() || !opts.is_read(),
+        };
+        if invalid {
+            return Err(VfsErr::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid open options {:?} for {}", opts, file.as_path().to_string_lossy()),
             )));
         }
-        match self.data.get(file.to_string_lossy().as_ref()) {
-            Some(data) => {
-                let data: &[u8] = data.as_bytes();
-                Ok(Box::new(MemVfsFile {
-                    path: file,
-                    data: Vec::from(data),
-                    offset: 0,
-                }))
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        let creating = !state.files.contains_key(file.as_path()) && (opts.is_create() || opts.is_create_new());
+        if let Some(parent) = file.as_path().parent().filter(|_| creating) {
+            if self.lazy_dirs {
+                state.create_dir_all(&self.root, parent);
+            } else if !state.is_dir(&self.root, parent) {
+                return Err(VfsErr::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Parent directory does not exist - {}", parent.to_string_lossy()),
+                )));
+            }
+        }
+        let (node, data) = match state.files.get(file.as_path()) {
+            Some(_) if opts.is_create_new() => {
+                return Err(VfsErr::Io(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("File already exists - {}", file.as_path().to_string_lossy()),
+                )));
+            }
+            Some(node) if !opts.is_truncate() => (node.clone(), node.bytes().map_err(VfsErr::Io)?.to_vec()),
+            Some(node) => {
+                node.set(Bytes::new()).map_err(VfsErr::Io)?;
+                self.notifier.notify(file.as_path());
+                (node.clone(), vec![])
+            }
+            None if state.is_dir(&self.root, file.as_path()) => {
+                return Err(VfsErr::Io(std::io::Error::new(
+                    std::io::ErrorKind::IsADirectory,
+                    file.as_path().to_string_lossy(),
+                )));
+            }
+            None if opts.is_create() || opts.is_create_new() => {
+                let node = MemNode::new(Bytes::new());
+                state.files.insert(file.as_path().to_path_buf(), node.clone());
+                self.notifier.notify(file.as_path());
+                (node, vec![])
             }
             None => {
-                //we assume write/append and create it - means there's a different behaviour with in-memory vs disk
-                Ok(Box::new(MemVfsFile {
-                    path: file,
-                    data: vec![],
-                    offset: 0,
-                }))
-                // Err(VfsErr::FileNotFound(format!(
-                //     "File not found - {}",
-                //     file.to_string_lossy()
-                // )))
+                return Err(VfsErr::FileNotFound(format!(
+                    "File not found - {}",
+                    file.as_path().to_string_lossy()
+                )));
             }
+        };
+        Ok(Box::new(MemVfsFile {
+            path: file,
+            node,
+            notifier: self.notifier.clone(),
+            data,
+            offset: 0,
+            readable: opts.is_read(),
+            writable,
+            append: opts.is_append(),
+            dirty: false,
+        }))
+    }
+
+    fn read_dir(&self, dir: &VfsPath) -> Result<VirtualReadDir> {
+        self.check_path(dir.as_path())?;
+        let state = self.state.read().map_err(|_| VfsErr::Io(poisoned()))?;
+        if !state.is_dir(&self.root, dir.as_path()) {
+            return Err(VfsErr::FileNotFound(format!(
+                "Directory not found - {}",
+                dir.as_path().to_string_lossy()
+            )));
+        }
+        //the first component under dir of every file or directory below it, its descendants sort right after it
+        let after = (Bound::Excluded(dir.as_path()), Bound::Unbounded);
+        let child = |path: &Path| {
+            let name = path.strip_prefix(dir.as_path()).ok()?.components().next()?;
+            Some(dir.as_path().join(name))
+        };
+        let files = state.files.range::<Path, _>(after).map(|(path, _)| path);
+        let dirs = state.dirs.range::<Path, _>(after);
+        let mut children = BTreeSet::new();
+        for path in files.take_while(|path| path.starts_with(dir)) {
+            children.extend(child(path));
+        }
+        for path in dirs.take_while(|path| path.starts_with(dir)) {
+            children.extend(child(path));
+        }
+        Ok(VirtualReadDir::new(dir, children.into_iter()))
+    }
+
+    fn lazy_dirs(&self) -> bool {
+        self.lazy_dirs
+    }
+
+    fn domain_index(&self) -> Option<&DomainIndex> {
+        Some(&self.domains)
+    }
+    fn watch(&self, service_id: i64, is_draft: bool, version: &str) -> Result<Watcher> {
+        Ok(self.notifier.watch(WatchTargets::new(self, service_id, is_draft, version)?))
+    }
+
+    fn create_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        if state.files.contains_key(dir.as_path()) {
+            return Err(VfsErr::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("A file already exists at {}", dir.as_path().to_string_lossy()),
+            )));
         }
+        state.create_dir_all(&self.root, dir.as_path());
+        Ok(())
     }
 
-    fn read_dir(&self, dir: &PathBuf) -> Result<VirtualReadDir> {
-        if dir.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot read dir with .. in path {}",
-                dir.to_string_lossy()
+    fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        if state.files.contains_key(dir.as_path()) || state.is_dir(&self.root, dir.as_path()) {
+            return Err(VfsErr::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Already exists - {}", dir.as_path().to_string_lossy()),
             )));
         }
-        let it: Vec<_> = self
-            .data
-            .keys()
-            .map(PathBuf::from)
-            .skip_while(|path| !path.starts_with(dir))
-            .collect();
-        Ok(VirtualReadDir {
-            inner: Box::new(it.into_iter()),
-        })
+        if let Some(parent) = dir.as_path().parent().filter(|p| !state.is_dir(&self.root, p)) {
+            return Err(VfsErr::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Parent directory does not exist - {}", parent.to_string_lossy()),
+            )));
+        }
+        state.dirs.insert(dir.as_path().to_path_buf());
+        Ok(())
+    }
+
+    fn remove_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        if !state.is_dir(&self.root, dir.as_path()) {
+            return Err(VfsErr::FileNotFound(format!(
+                "Directory not found - {}",
+                dir.as_path().to_string_lossy()
+            )));
+        }
+        if state.has_children(dir.as_path()) {
+            return Err(VfsErr::Io(std::io::Error::new(
+                std::io::ErrorKind::DirectoryNotEmpty,
+                format!("Directory not empty - {}", dir.as_path().to_string_lossy()),
+            )));
+        }
+        state.dirs.remove(dir.as_path());
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        self.check_path(dir.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        if dir.as_path() == self.root || !state.is_dir(&self.root, dir.as_path()) {
+            return Err(VfsErr::FileNotFound(format!(
+                "Directory not found - {}",
+                dir.as_path().to_string_lossy()
+            )));
+        }
+        state.files.retain(|path, _| {
+            let removed = path.starts_with(dir);
+            if removed {
+                self.notifier.notify(path);
+            }
+            !removed
+        });
+        state.dirs.retain(|path| !path.starts_with(dir));
+        Ok(())
+    }
+
+    fn metadata(&self, path: &VfsPath) -> Result<VfsMetadata> {
+        self.check_path(path.as_path())?;
+        let state = self.state.read().map_err(|_| VfsErr::Io(poisoned()))?;
+        if let Some(node) = state.files.get(path.as_path()) {
+            return node.metadata().map_err(VfsErr::Io);
+        }
+        if state.is_dir(&self.root, path.as_path()) {
+            return Ok(VfsMetadata {
+                len: 0,
+                kind: VfsFileKind::Dir,
+                readonly: false,
+                modified: None,
+                created: None,
+            });
+        }
+        Err(VfsErr::FileNotFound(format!(
+            "File not found - {}",
+            path.as_path().to_string_lossy()
+        )))
+    }
+
+    fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        self.check_path(from.as_path())?;
+        self.check_path(to.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        if let Some(parent) = to.as_path().parent() {
+            if self.lazy_dirs {
+                state.create_dir_all(&self.root, parent);
+            } else if !state.is_dir(&self.root, parent) {
+                return Err(VfsErr::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Parent directory does not exist - {}", parent.to_string_lossy()),
+                )));
+            }
+        }
+        //a file, or everything in a directory
+        let moved: Vec<_> = state.files.keys().filter(|p| p.starts_with(from)).cloned().collect();
+        state.rename(&self.root, from.as_path(), to.as_path()).map_err(VfsErr::Io)?;
+        for path in moved {
+            let rel = path.strip_prefix(from).unwrap_or(&path);
+            self.notifier.notify(&path);
+            match rel.as_os_str().is_empty() {
+                true => self.notifier.notify(to.as_path()),
+                false => self.notifier.notify(&to.as_path().join(rel)),
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        self.check_path(file.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        match state.files.remove(file.as_path()) {
+            Some(_) => {
+                self.notifier.notify(file.as_path());
+                Ok(())
+            }
+            None => Err(VfsErr::FileNotFound(format!(
+                "File not found - {}",
+                file.as_path().to_string_lossy()
+            ))),
+        }
+    }
+
+    fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        self.check_path(from.as_path())?;
+        self.check_path(to.as_path())?;
+        let mut state = self.state.write().map_err(|_| VfsErr::Io(poisoned()))?;
+        let data = match state.files.get(from.as_path()) {
+            Some(node) => node.bytes().map_err(VfsErr::Io)?,
+            None => {
+                return Err(VfsErr::FileNotFound(format!(
+                    "File not found - {}",
+                    from.as_path().to_string_lossy()
+                )))
+            }
+        };
+        if state.is_dir(&self.root, to.as_path()) {
+            return Err(VfsErr::Io(std::io::Error::new(
+                std::io::ErrorKind::IsADirectory,
+                to.as_path().to_string_lossy(),
+            )));
+        }
+        if let Some(parent) = to.as_path().parent() {
+            if self.lazy_dirs {
+                state.create_dir_all(&self.root, parent);
+            } else if !state.is_dir(&self.root, parent) {
+                return Err(VfsErr::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Parent directory does not exist - {}", parent.to_string_lossy()),
+                )));
+            }
+        }
+        let copied = data.len() as u64;
+        //the copy shares the same bytes until one of them is written to
+        state.files.insert(to.as_path().to_path_buf(), MemNode::new(data));
+        self.notifier.notify(to.as_path());
+        Ok(copied)
     }
 }
 
@@ -562,11 +1837,34 @@ impl<F> BoundVfs<F>
             .read_schema_file(self.options.service_id, self.options.is_draft, self.options.version.as_str(), name)
     }
 
-    pub fn ecma_files(&self) -> Result<DirStream<F>> {
+    ///`schema.xml` of the bound version and everything it imports, see [Vfs::load_schema_bundle]
+    pub fn load_schema_bundle(&self) -> Result<IndexMap<String, String>> {
+        self.vfs
+            .load_schema_bundle(self.options.service_id, self.options.is_draft, self.options.version.as_str())
+    }
+
+    pub fn ecma_files(&self) -> Result<DirStream<'_, F>> {
         self.vfs
             .read_ecma(self.options.service_id, self.options.is_draft, self.options.version.as_str())
     }
 
+    ///Resolves the `import` specifiers of the bound version's ECMA scripts, see [EcmaResolver]
+    pub fn ecma_resolver(&self) -> Result<EcmaResolver<'_, F>> {
+        EcmaResolver::new(self.vfs.as_ref(), self.options.service_id, self.options.is_draft, self.options.version.as_str())
+    }
+
+    ///The scripts of the bound version with their hashes, see [Vfs::ecma_manifest]
+    pub fn ecma_manifest(&self) -> Result<EcmaManifest> {
+        self.vfs
+            .ecma_manifest(self.options.service_id, self.options.is_draft, self.options.version.as_str())
+    }
+
+    ///Watches the bound version, its service's resources and plugins for changes, see [Vfs::watch]
+    pub fn watch(&self) -> Result<Watcher> {
+        self.vfs
+            .watch(self.options.service_id, self.options.is_draft, self.options.version.as_str())
+    }
+
     pub fn read_ecma_file(&self, mut file: PathBuf) -> Result<String> {
         if file.starts_with("./") {
             file = file
@@ -574,109 +1872,232 @@ impl<F> BoundVfs<F>
                 .map_err(VfsErr::StripPrefixErr)?
                 .to_owned();
         }
-        let mut path = self
+        validate_relative(&file)?;
+        let path = self
             .vfs
-            .ecma_dir(self.options.service_id, self.options.is_draft, self.options.version.as_str())?;
-        path.push(file);
+            .ecma_dir(self.options.service_id, self.options.is_draft, self.options.version.as_str())?
+            .join(file)?;
         let mut read = self.vfs.read(path)?;
         let mut str = String::new();
         read.read_to_string(&mut str).map_err(VfsErr::Io)?;
         Ok(str)
     }
 
-    pub fn resource_dir(&self) -> Result<PathBuf> {
+    pub fn resource_dir(&self) -> Result<VfsPath> {
         self.vfs.resource_dir(self.options.service_id)
     }
 
-    pub fn resolve_resource(&self, mut file: PathBuf) -> Result<PathBuf> {
+    pub fn resolve_resource(&self, mut file: PathBuf) -> Result<VfsPath> {
         if file.starts_with("./") {
             file = file
                 .strip_prefix("./")
                 .map_err(VfsErr::StripPrefixErr)?
                 .to_owned();
-        } else if file.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot open file with .. in path {}",
-                file.to_string_lossy()
-            )));
         }
-        let mut path = self.vfs.resource_dir(self.options.service_id)?;
-        path.push(file);
-        Ok(path)
+        self.vfs.resource_dir(self.options.service_id)?.join(file)
     }
-    pub fn resolve_plugin(&self, mut file: PathBuf) -> Result<PathBuf> {
+    pub fn resolve_plugin(&self, mut file: PathBuf) -> Result<VfsPath> {
         if file.starts_with("./") {
             file = file
                 .strip_prefix("./")
                 .map_err(VfsErr::StripPrefixErr)?
                 .to_owned();
-        } else if file.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot open file with .. in path {}",
-                file.to_string_lossy()
-            )));
         }
-        let mut path = self.vfs.plugins_dir(self.options.service_id)?;
-        path.push(file);
-        Ok(path)
+        self.vfs.plugins_dir(self.options.service_id)?.join(file)
     }
-    pub fn open(&self, mut file: PathBuf, opts: OpenOptions) -> Result<Box<dyn VfsFile>> {
-        if file.starts_with("./") {
-            file = file
-                .strip_prefix("./")
-                .map_err(VfsErr::StripPrefixErr)?
-                .to_owned();
-        } else if file.to_string_lossy().contains("..") {
-            return Err(VfsErr::DotPathsNotSupported(format!(
-                "Cannot open file with .. in path {}",
-                file.to_string_lossy()
-            )));
-        }
+    ///The whole content of a resource, see [Vfs::read_bytes]
+    pub fn read_resource_bytes(&self, file: PathBuf) -> Result<Bytes> {
+        self.vfs.read_bytes(self.resolve_resource(file)?)
+    }
+    pub fn open(&self, file: PathBuf, opts: VfsOpenOptions) -> Result<Box<dyn VfsFile>> {
         self.vfs.open_with(self.resolve_resource(file)?, opts)
     }
+    ///An [AtomicWriter] which replaces the resource `file` when committed
+    pub fn resource_writer(&self, file: PathBuf) -> Result<AtomicWriter<'_, F>> {
+        self.vfs.atomic_writer(self.resolve_resource(file)?)
+    }
+    ///An [AtomicWriter] which replaces the schema file `name` in the bound version when committed
+    pub fn schema_writer(&self, name: &str) -> Result<AtomicWriter<'_, F>> {
+        let target = self.vfs.schema_file(
+            self.options.service_id,
+            self.options.is_draft,
+            self.options.version.as_str(),
+            name,
+        )?;
+        self.vfs.atomic_writer(target)
+    }
+    ///Checks `xml` and every file it imports, directly or not, are well-formed, see
+    ///[check_well_formed](crate::validate::check_well_formed), then atomically replaces the schema file `name` in the
+    ///bound draft with it. Imports which haven't been written yet are skipped, publishing checks the whole bundle.
+    ///Fails with [VfsErr::VersionIsPublished] if the bound version isn't a draft.
+    pub fn write_schema_file(&self, name: &str, xml: &str) -> Result<()> {
+        if !self.options.is_draft {
+            return Err(VfsErr::VersionIsPublished(self.options.version.clone()));
+        }
+        crate::validate::check_with_imports(
+            self.vfs.as_ref(),
+            self.options.service_id,
+            self.options.version.as_str(),
+            name,
+            xml,
+        )?;
+        let mut writer = self.schema_writer(name)?;
+        writer.write_all(xml.as_bytes()).map_err(VfsErr::Io)?;
+        writer.commit()
+    }
 
-    pub fn discard<I>(&self, _file: &I) -> Result<()>
+    ///Creates an empty file in the service's [TMP_SUBDIR] to upload into.
+    ///Commit it to the service's resources with [BoundVfs::save_to] or delete it with [BoundVfs::discard],
+    ///if neither is called the file is deleted when it's dropped.
+    pub fn create_temp(&self) -> Result<TempVfsFile<F>> {
+        let path = self.vfs.tmp_file(self.options.service_id)?;
+        let mut opts = VfsOpenOptions::new();
+        opts.read(true).write(true).create_new(true);
+        let file = self.vfs.open_with(path, opts)?;
+        Ok(TempVfsFile {
+            file,
+            vfs: self.vfs.clone(),
+        })
+    }
+
+    ///Deletes `file`, which must belong to the bound service
+    pub fn discard<I>(&self, file: &I) -> Result<()>
         where
             I: VfsFile + ?Sized,
     {
-        todo!();
-        // Ok(())
+        let path = file.path();
+        self.check_service(&path)?;
+        self.vfs.remove_file(&path)
     }
-    pub fn save_to<I>(&self, file: &I, new_name: Option<String>) -> Result<String>
+    ///Moves `file` into the service's resources, keeping its name unless `new_name` is given, and returns the name it was saved as.
+    ///A file already in the resources directory keeps its sub-directory, anything else (e.g. from [BoundVfs::create_temp])
+    ///is saved at the top of the resources directory.
+    pub fn save_to<I>(&self, file: &mut I, new_name: Option<String>) -> Result<String>
         where
             I: VfsFile + ?Sized,
     {
-        let mut other_path = file.path();
-        let mut path = self.vfs.resource_dir(self.options.service_id)?;
-        if other_path.starts_with(&path) {
-            other_path = PathBuf::from(
-                other_path
-                    .strip_prefix(&path)
-                    .map_err(VfsErr::StripPrefixErr)?,
-            );
-        }
-        if other_path.starts_with(TMP_SUBDIR) {
-            other_path = PathBuf::from(
-                other_path
-                    .strip_prefix(TMP_SUBDIR)
-                    .map_err(VfsErr::StripPrefixErr)?,
-            )
-        }
-        path.push(other_path);
+        let source = file.path();
+        self.check_service(&source)?;
+        file.flush().map_err(VfsErr::Io)?;
+        let dir = self.vfs.resource_dir(self.options.service_id)?;
+        let current_name = match source.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => return Err(VfsErr::FileNotFound(source.as_path().to_string_lossy().to_string())),
+        };
+        let mut rel = match source.as_path().strip_prefix(dir.as_path()) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => current_name,
+        };
         if let Some(file_name) = new_name {
-            path.set_file_name(file_name);
+            rel.set_file_name(file_name);
+        }
+        let target = dir.join(&rel)?;
+        let name = target
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match self.vfs.rename(&source, &target) {
+            //e.g. .tmp is a mount of its own, an upload isn't visible until it's saved so it can be copied
+            Err(VfsErr::Io(e)) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                copy_then_remove(self.vfs.as_ref(), &source, &target)?
+            }
+            res => res?,
         }
-        let name = if let Some(name) = path.file_name().map(|v| v.to_str()).flatten() {
-            name.to_string()
-        } else {
-            file.path()
-                .to_string_lossy()
-                .split("/")
-                .last()
-                .unwrap()
-                .to_string()
-        };
-        fs::rename(file.path(), path).map_err(VfsErr::Io)?;
         Ok(name)
     }
+
+    ///Resolves `child` against the bound service's directory, e.g. `files/logo.png`
+    pub fn resolve(&self, child: &str) -> Result<VfsPath> {
+        self.vfs.resolve(format!("{}/{}", self.options.service_id, child).as_str())
+    }
+    ///Creates a directory of the bound service, see [Vfs::create_dir]
+    pub fn create_dir(&self, dir: &VfsPath) -> Result<()> {
+        self.check_service(dir)?;
+        self.vfs.create_dir(dir)
+    }
+    ///Removes a file of the bound service, see [Vfs::remove_file]
+    pub fn remove_file(&self, file: &VfsPath) -> Result<()> {
+        self.check_service(file)?;
+        self.vfs.remove_file(file)
+    }
+    ///Removes a directory of the bound service and everything in it, see [Vfs::remove_dir_all]
+    pub fn remove_dir_all(&self, dir: &VfsPath) -> Result<()> {
+        self.check_service(dir)?;
+        self.vfs.remove_dir_all(dir)
+    }
+    ///Moves a file or directory within the bound service, see [Vfs::rename]
+    pub fn rename(&self, from: &VfsPath, to: &VfsPath) -> Result<()> {
+        self.check_service(from)?;
+        self.check_service(to)?;
+        self.vfs.rename(from, to)
+    }
+    ///Copies a file within the bound service, see [Vfs::copy]
+    pub fn copy(&self, from: &VfsPath, to: &VfsPath) -> Result<u64> {
+        self.check_service(from)?;
+        self.check_service(to)?;
+        self.vfs.copy(from, to)
+    }
+
+    ///Fails with [VfsErr::ServiceMismatch] unless `path` belongs to the bound service
+    fn check_service(&self, path: &VfsPath) -> Result<()> {
+        if path.service_id() != Some(self.options.service_id) {
+            return Err(VfsErr::ServiceMismatch(path.as_path().to_string_lossy().to_string()));
+        }
+        Ok(())
+    }
+}
+
+///A file in a service's [TMP_SUBDIR] created by [BoundVfs::create_temp].
+///It is deleted when dropped, which is a no-op if it was already saved or discarded.
+pub struct TempVfsFile<F>
+    where
+        F: Vfs,
+{
+    file: Box<dyn VfsFile>,
+    vfs: Arc<F>,
+}
+
+impl<F: Vfs> Read for TempVfsFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl<F: Vfs> Write for TempVfsFile<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<F: Vfs> Seek for TempVfsFile<F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl<F: Vfs> VfsFile for TempVfsFile<F> {
+    fn path(&self) -> VfsPath {
+        self.file.path()
+    }
+    fn clone(&self) -> Result<Box<dyn VfsFile>> {
+        self.file.clone()
+    }
+}
+
+impl<F: Vfs> Drop for TempVfsFile<F> {
+    fn drop(&mut self) {
+        match self.vfs.remove_file(&self.file.path()) {
+            Ok(()) => {}
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => warn!(
+                "Failed to clean up temp file {} - {}",
+                self.file.path().as_path().to_string_lossy(),
+                e
+            ),
+        }
+    }
 }
@@ -0,0 +1,427 @@
+//! Change notifications for the files of a draft or version, see [Vfs::watch].
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::atomic::is_atomic_tmp;
+use crate::vfs::{Result, Vfs, VfsPath, PLUGINS_SUBDIR, RESOURCES_SUBDIR};
+
+///How long no file has to change before the changes seen so far are sent as [WatchEvent]s
+pub const DEBOUNCE: Duration = Duration::from_millis(100);
+///Changes are sent after this long even if files keep changing
+const MAX_DELAY: Duration = Duration::from_secs(1);
+///How often an idle watcher checks whether its [Watcher] was dropped
+const IDLE_CHECK: Duration = Duration::from_millis(250);
+
+///Which part of a service a changed file belongs to, so that only that part has to be reloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum WatchKind {
+    ///`schema.xml`, the files it imports or anything else in the version outside [ECMA_SUBDIR](crate::vfs::ECMA_SUBDIR)
+    Schema,
+    ///A file in the version's [ECMA_SUBDIR](crate::vfs::ECMA_SUBDIR)
+    Ecma,
+    ///A file in the service's [RESOURCES_SUBDIR]
+    Resource,
+    ///A file in the service's [PLUGINS_SUBDIR]
+    Plugin,
+}
+
+///Files of one [WatchKind] which were created, modified, removed or renamed since the previous event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    pub kind: WatchKind,
+    ///The files which changed, or a directory when the whole directory was removed or renamed.
+    ///Every directory of this kind is listed if the backend lost track of what changed.
+    pub paths: BTreeSet<VfsPath>,
+}
+
+///Receives the [WatchEvent]s of a [Vfs::watch]. Changes stop being watched when it's dropped.
+pub struct Watcher {
+    events: Receiver<WatchEvent>,
+    ///The backend's thread stops once it can't upgrade its [Weak] reference to this
+    _alive: Arc<()>,
+}
+
+impl Watcher {
+    ///Waits for the next event, [None] if the backend stopped watching
+    pub fn recv(&self) -> Option<WatchEvent> {
+        self.events.recv().ok()
+    }
+    ///Waits up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+    ///The next event if there is one already
+    pub fn try_recv(&self) -> Option<WatchEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+///The directories a [Watcher] reports changes in
+#[derive(Debug, Clone)]
+pub(crate) struct WatchTargets {
+    service_id: i64,
+    version: VfsPath,
+    ecma: VfsPath,
+    resources: VfsPath,
+    plugins: VfsPath,
+}
+
+impl WatchTargets {
+    pub(crate) fn new<F: Vfs + ?Sized>(vfs: &F, service_id: i64, is_draft: bool, version: &str) -> Result<Self> {
+        crate::version::validate_version_name(version)?;
+        Ok(WatchTargets {
+            service_id,
+            version: vfs.version_dir(service_id, is_draft, version)?,
+            ecma: vfs.ecma_dir(service_id, is_draft, version)?,
+            //resolved rather than using resource_dir and plugins_dir which create them
+            resources: vfs.resolve(format!("{}/{}", service_id, RESOURCES_SUBDIR).as_str())?,
+            plugins: vfs.resolve(format!("{}/{}", service_id, PLUGINS_SUBDIR).as_str())?,
+        })
+    }
+    ///The directories whose whole tree is watched
+    #[cfg(target_os = "linux")]
+    pub(crate) fn roots(&self) -> [&Path; 3] {
+        [self.version.as_path(), self.resources.as_path(), self.plugins.as_path()]
+    }
+    pub(crate) fn kind(&self, path: &Path) -> Option<WatchKind> {
+        if path.starts_with(self.ecma.as_path()) {
+            Some(WatchKind::Ecma)
+        } else if path.starts_with(self.version.as_path()) {
+            Some(WatchKind::Schema)
+        } else if path.starts_with(self.resources.as_path()) {
+            Some(WatchKind::Resource)
+        } else if path.starts_with(self.plugins.as_path()) {
+            Some(WatchKind::Plugin)
+        } else {
+            None
+        }
+    }
+}
+
+///Collects the paths a backend reports and sends them as [WatchEvent]s once they stop changing
+pub(crate) struct Debouncer {
+    targets: WatchTargets,
+    pending: BTreeMap<WatchKind, BTreeSet<VfsPath>>,
+    ///When the first and the latest pending change were seen
+    first: Option<Instant>,
+    last: Option<Instant>,
+    events: Sender<WatchEvent>,
+    alive: Weak<()>,
+}
+
+impl Debouncer {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn targets(&self) -> &WatchTargets {
+        &self.targets
+    }
+    ///Records a change to `path`, ignored if it isn't in one of the watched directories or is an uncommitted write
+    pub(crate) fn add(&mut self, path: PathBuf) {
+        if is_atomic_tmp(&path) {
+            return;
+        }
+        let Some(kind) = self.targets.kind(&path) else {
+            return;
+        };
+        let now = Instant::now();
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+        self.pending
+            .entry(kind)
+            .or_default()
+            .insert(VfsPath::new(path, Some(self.targets.service_id)));
+    }
+    ///Records a change to everything, for when the backend dropped some notifications
+    #[cfg(target_os = "linux")]
+    pub(crate) fn add_all(&mut self) {
+        let WatchTargets { ecma, version, resources, plugins, .. } = self.targets.clone();
+        for dir in [ecma, version, resources, plugins] {
+            self.add(dir.into_path_buf());
+        }
+    }
+    ///How long the backend can wait for more changes before calling [Debouncer::flush]
+    pub(crate) fn timeout(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                let deadline = (last + DEBOUNCE).min(first + MAX_DELAY);
+                deadline.saturating_duration_since(Instant::now())
+            }
+            _ => IDLE_CHECK,
+        }
+    }
+    ///Sends the pending changes if they're due, false once the [Watcher] has been dropped
+    pub(crate) fn flush(&mut self) -> bool {
+        if self.alive.strong_count() == 0 {
+            return false;
+        }
+        if self.pending.is_empty() || !self.timeout().is_zero() {
+            return true;
+        }
+        self.first = None;
+        self.last = None;
+        for (kind, paths) in std::mem::take(&mut self.pending) {
+            if self.events.send(WatchEvent { kind, paths }).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+///Creates a [Watcher] and the [Debouncer] a backend feeds it through
+pub(crate) fn watcher(targets: WatchTargets) -> (Watcher, Debouncer) {
+    let (tx, rx) = channel();
+    let alive = Arc::new(());
+    let debouncer = Debouncer {
+        targets,
+        pending: BTreeMap::new(),
+        first: None,
+        last: None,
+        events: tx,
+        alive: Arc::downgrade(&alive),
+    };
+    (Watcher { events: rx, _alive: alive }, debouncer)
+}
+
+///Tells every [Watcher] of a [MemoryVfs](crate::MemoryVfs), and its clones, which files were written
+#[derive(Clone, Default)]
+pub(crate) struct Notifier {
+    subscribers: Arc<Mutex<Vec<Sender<PathBuf>>>>,
+}
+
+impl Notifier {
+    pub(crate) fn notify(&self, path: &Path) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        //a watcher which stopped has dropped its receiver
+        subscribers.retain(|s| s.send(path.to_path_buf()).is_ok());
+    }
+
+    ///Starts a thread which debounces the changes to `targets`
+    pub(crate) fn watch(&self, targets: WatchTargets) -> Watcher {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(tx);
+        let (watcher, mut debouncer) = watcher(targets);
+        std::thread::spawn(move || loop {
+            match rx.recv_timeout(debouncer.timeout()) {
+                Ok(path) => debouncer.add(path),
+                Err(RecvTimeoutError::Timeout) => {}
+                //every clone of the MemoryVfs is gone
+                Err(RecvTimeoutError::Disconnected) => {
+                    debouncer.flush();
+                    return;
+                }
+            }
+            if !debouncer.flush() {
+                return;
+            }
+        });
+        watcher
+    }
+}
+
+///Watches a [FilesystemVfs](crate::FilesystemVfs) with inotify. The version, resources and plugins directories are
+///watched recursively and their parents up to the root are watched for them being created or renamed.
+#[cfg(target_os = "linux")]
+pub(crate) mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::{OsStr, OsString};
+    use std::mem::MaybeUninit;
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    use log::warn;
+    use rustix::event::{poll, PollFd, PollFlags, Timespec};
+    use rustix::fs::inotify::{add_watch, init, remove_watch, CreateFlags, ReadFlags, Reader, WatchFlags};
+    use rustix::fs::OFlags;
+    use rustix::io::Errno;
+
+    use super::{watcher, Debouncer, WatchTargets, Watcher};
+    use crate::vfs::{is_not_found, Result, VfsErr};
+
+    const MASK: WatchFlags = WatchFlags::CREATE
+        .union(WatchFlags::DELETE)
+        .union(WatchFlags::MODIFY)
+        .union(WatchFlags::CLOSE_WRITE)
+        .union(WatchFlags::MOVED_FROM)
+        .union(WatchFlags::MOVED_TO)
+        .union(WatchFlags::ONLYDIR);
+
+    struct Inotify {
+        fd: OwnedFd,
+        root: PathBuf,
+        hardened: bool,
+        watches: HashMap<i32, PathBuf>,
+        debouncer: Debouncer,
+    }
+
+    pub(crate) fn watch(root: &Path, hardened: bool, targets: WatchTargets) -> Result<Watcher> {
+        let fd = init(CreateFlags::CLOEXEC | CreateFlags::NONBLOCK).map_err(|e| VfsErr::Io(e.into()))?;
+        let (watcher, debouncer) = watcher(targets);
+        let mut inotify = Inotify {
+            fd,
+            root: root.to_path_buf(),
+            hardened,
+            watches: HashMap::new(),
+            debouncer,
+        };
+        //changes made before the thread starts are caught because the watches are added first
+        inotify.watch_dir(root.to_path_buf(), false)?;
+        std::thread::spawn(move || {
+            if let Err(e) = inotify.run() {
+                warn!("Stopped watching {} - {}", inotify.root.to_string_lossy(), e);
+            }
+        });
+        Ok(watcher)
+    }
+
+    impl Inotify {
+        ///True if `dir` is, or is in, one of the watched directories
+        fn is_watched_tree(&self, dir: &Path) -> bool {
+            self.debouncer.targets().roots().iter().any(|root| dir.starts_with(root))
+        }
+        ///True if `dir` is a parent of one of the watched directories, so they can be seen being created
+        fn is_parent(&self, dir: &Path) -> bool {
+            dir.starts_with(&self.root) && self.debouncer.targets().roots().iter().any(|root| root.starts_with(dir))
+        }
+
+        ///Watches `dir` and the directories in it which lead to or are in the watched trees.
+        ///When `report` is true, every file already in `dir` is reported as changed, since it was created or moved
+        ///in before the watch was added.
+        fn watch_dir(&mut self, dir: PathBuf, report: bool) -> Result<()> {
+            let in_tree = self.is_watched_tree(&dir);
+            if !in_tree && !self.is_parent(&dir) {
+                return Ok(());
+            }
+            //the directory may already have been removed again
+            match self.add_watch(&dir) {
+                Ok(wd) => {
+                    self.watches.insert(wd, dir.clone());
+                }
+                Err(e) if is_not_found(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let entries = match self.entries(&dir) {
+                Ok(entries) => entries,
+                Err(e) if is_not_found(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            for (path, is_dir) in entries {
+                if is_dir {
+                    self.watch_dir(path, report)?;
+                } else if report && in_tree {
+                    self.debouncer.add(path);
+                }
+            }
+            Ok(())
+        }
+
+        fn add_watch(&self, dir: &Path) -> Result<i32> {
+            let res = if self.hardened {
+                //watch the directory opened without following symlinks through its handle, not by its path
+                let rel = dir.strip_prefix(&self.root).map_err(VfsErr::StripPrefixErr)?;
+                let handle = crate::beneath::open(&self.root, rel, OFlags::PATH | OFlags::DIRECTORY)?;
+                add_watch(&self.fd, format!("/proc/self/fd/{}", handle.as_raw_fd()), MASK)
+            } else {
+                add_watch(&self.fd, dir, MASK)
+            };
+            res.map_err(|e| VfsErr::Io(e.into()))
+        }
+
+        ///The entries of `dir` and whether each is a directory, symlinks aren't followed in hardened mode
+        fn entries(&self, dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+            let mut entries = vec![];
+            if self.hardened {
+                let rel = dir.strip_prefix(&self.root).map_err(VfsErr::StripPrefixErr)?;
+                for path in crate::beneath::read_dir(&self.root, rel)? {
+                    let rel = path.strip_prefix(&self.root).map_err(VfsErr::StripPrefixErr)?;
+                    let is_dir = crate::beneath::metadata(&self.root, rel).is_ok_and(|m| m.is_dir());
+                    entries.push((path, is_dir));
+                }
+            } else {
+                for entry in std::fs::read_dir(dir).map_err(VfsErr::Io)? {
+                    let path = entry.map_err(VfsErr::Io)?.path();
+                    let is_dir = path.is_dir();
+                    entries.push((path, is_dir));
+                }
+            }
+            Ok(entries)
+        }
+
+        fn run(&mut self) -> Result<()> {
+            let mut buf = [MaybeUninit::uninit(); 8192];
+            loop {
+                let timeout = self.debouncer.timeout();
+                let timeout = Timespec {
+                    tv_sec: timeout.as_secs() as i64,
+                    tv_nsec: timeout.subsec_nanos() as i64,
+                };
+                match poll(&mut [PollFd::new(&self.fd, PollFlags::IN)], Some(&timeout)) {
+                    Ok(_) | Err(Errno::INTR) => {}
+                    Err(e) => return Err(VfsErr::Io(e.into())),
+                }
+                //copied out because handling them needs self
+                let mut events: Vec<(i32, ReadFlags, Option<OsString>)> = vec![];
+                let mut reader = Reader::new(&self.fd, &mut buf);
+                loop {
+                    match reader.next() {
+                        Ok(event) => {
+                            let name = event.file_name().map(|n| OsStr::from_bytes(n.to_bytes()).to_os_string());
+                            events.push((event.wd(), event.events(), name));
+                        }
+                        Err(Errno::AGAIN) => break,
+                        Err(e) => return Err(VfsErr::Io(e.into())),
+                    }
+                }
+                for (wd, flags, name) in events {
+                    self.handle(wd, flags, name)?;
+                }
+                if !self.debouncer.flush() {
+                    return Ok(());
+                }
+            }
+        }
+
+        fn handle(&mut self, wd: i32, flags: ReadFlags, name: Option<OsString>) -> Result<()> {
+            if flags.contains(ReadFlags::QUEUE_OVERFLOW) {
+                self.debouncer.add_all();
+                return Ok(());
+            }
+            if flags.contains(ReadFlags::IGNORED) {
+                self.watches.remove(&wd);
+                return Ok(());
+            }
+            let Some(dir) = self.watches.get(&wd) else {
+                return Ok(());
+            };
+            let path = match name {
+                Some(name) => dir.join(name),
+                None => dir.clone(),
+            };
+            if !flags.contains(ReadFlags::ISDIR) {
+                self.debouncer.add(path);
+            } else if flags.intersects(ReadFlags::CREATE | ReadFlags::MOVED_TO) {
+                self.watch_dir(path, true)?;
+            } else if flags.intersects(ReadFlags::DELETE | ReadFlags::MOVED_FROM) {
+                //the watches of a directory moved elsewhere would report it under its old name
+                let moved: Vec<_> = self.watches.iter().filter(|(_, p)| p.starts_with(&path)).map(|(wd, _)| *wd).collect();
+                for wd in moved {
+                    self.watches.remove(&wd);
+                    let _ = remove_watch(&self.fd, wd);
+                }
+                self.debouncer.add(path);
+            }
+            Ok(())
+        }
+    }
+}
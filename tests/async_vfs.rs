@@ -0,0 +1,169 @@
+#![cfg(feature = "async")]
+use std::collections::BTreeSet;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use rapid_fs::async_vfs::{AsyncVfs, BlockingVfs};
+use rapid_fs::vfs::{DomainOptions, Vfs, VfsErr, VfsOpenOptions};
+use rapid_fs::{CachingVfs, FilesystemVfs, MemoryVfs};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+async fn round_trip<V: AsyncVfs>(vfs: V) {
+    let dir = vfs.resolve("123/drafts/dev/ecma/lib").unwrap();
+    vfs.create_dir_all(&dir).await.unwrap();
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    let mut file = vfs.open_with(dir.join("util.js").unwrap(), opts.clone()).await.unwrap();
+    assert_eq!(file.path(), dir.join("util.js").unwrap());
+    file.write_all(b"export const a = 1;").await.unwrap();
+    file.sync_all().await.unwrap();
+    drop(file);
+    //bigger than a chunk of BlockingVfs
+    let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    let mut file = vfs.open_with(dir.join("big.js").unwrap(), opts).await.unwrap();
+    file.write_all(&big).await.unwrap();
+    file.flush().await.unwrap();
+    drop(file);
+    let schema = vfs.schema_file(123, true, "dev", "schema.xml").unwrap();
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create_new(true);
+    let mut file = vfs.open_with(schema, opts).await.unwrap();
+    file.write_all(b"<document/>").await.unwrap();
+    file.shutdown().await.unwrap();
+    drop(file);
+
+    assert_eq!(vfs.read_to_end(dir.join("util.js").unwrap()).await.unwrap(), b"export const a = 1;");
+    assert_eq!(vfs.read_to_end(dir.join("big.js").unwrap()).await.unwrap(), big);
+    assert_eq!(vfs.read_bytes(dir.join("big.js").unwrap()).await.unwrap(), big);
+    assert_eq!(vfs.read_schema_file(123, true, "dev", "schema.xml").await.unwrap(), "<document/>");
+    assert_eq!(vfs.metadata(&dir.join("big.js").unwrap()).await.unwrap().len(), big.len() as u64);
+    assert!(vfs.metadata(&dir).await.unwrap().is_dir());
+    assert!(vfs.exists(&dir.join("big.js").unwrap()).await.unwrap());
+    match vfs.read(dir.join("missing.js").unwrap()).await {
+        Err(VfsErr::FileNotFound(_)) | Err(VfsErr::Io(_)) => {}
+        _ => panic!("Expected reading a missing file to fail"),
+    }
+
+    //reads, seeks and writes on one handle
+    let mut opts = VfsOpenOptions::new();
+    opts.read(true).write(true);
+    let mut file = vfs.open_with(dir.join("util.js").unwrap(), opts).await.unwrap();
+    let mut start = [0; 6];
+    file.read_exact(&mut start).await.unwrap();
+    assert_eq!(&start, b"export");
+    assert_eq!(file.stream_position().await.unwrap(), 6);
+    file.seek(SeekFrom::End(-2)).await.unwrap();
+    file.write_all(b"2;").await.unwrap();
+    file.seek(SeekFrom::Start(0)).await.unwrap();
+    let mut content = String::new();
+    file.read_to_string(&mut content).await.unwrap();
+    assert_eq!(content, "export const a = 2;");
+    file.sync_all().await.unwrap();
+    drop(file);
+
+    let ecma = vfs.ecma_dir(123, true, "dev").unwrap();
+    let files: BTreeSet<String> = vfs
+        .dir_stream(ecma.clone())
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().rel.to_string_lossy().into_owned())
+        .collect()
+        .await;
+    assert_eq!(files, BTreeSet::from(["lib/big.js".to_owned(), "lib/util.js".to_owned()]));
+    assert_eq!(vfs.dir_stream_with_max_depth(ecma.clone(), 0).await.unwrap().count().await, 0);
+
+    let moved = ecma.join("util.mjs").unwrap();
+    vfs.rename(&dir.join("util.js").unwrap(), &moved).await.unwrap();
+    assert_eq!(vfs.read_to_end(moved.clone()).await.unwrap(), b"export const a = 2;");
+    let copy = ecma.join("copy/util.mjs").unwrap();
+    vfs.create_dir(&ecma.join("copy").unwrap()).await.unwrap();
+    assert_eq!(vfs.copy(&moved, &copy).await.unwrap(), 19);
+    assert_eq!(vfs.read_to_end(copy).await.unwrap(), b"export const a = 2;");
+    vfs.remove_file(&moved).await.unwrap();
+    assert!(!vfs.exists(&moved).await.unwrap());
+    vfs.remove_dir_all(&ecma).await.unwrap();
+    assert!(vfs.metadata(&ecma).await.is_err());
+}
+
+async fn write_file<V: AsyncVfs>(vfs: &V, path: &str, data: &[u8]) {
+    let parent = Path::new(path).parent().unwrap().to_str().unwrap();
+    vfs.create_dir_all(&vfs.resolve(parent).unwrap()).await.unwrap();
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    let mut file = vfs.open_with(vfs.resolve(path).unwrap(), opts).await.unwrap();
+    file.write_all(data).await.unwrap();
+    file.shutdown().await.unwrap();
+}
+
+async fn layout<V: AsyncVfs>(vfs: V) {
+    write_file(&vfs, "domains/api.hypi.ai", br#"{"service_id":123,"version":"v1"}"#).await;
+    write_file(&vfs, "domains/*.shop.hypi.ai", br#"{"service_id":456,"version":"dev","is_draft":true}"#).await;
+    write_file(&vfs, "domains/alias.hypi.ai", br#"{"alias_of":"api.hypi.ai"}"#).await;
+    assert_eq!(vfs.domain_file("api.hypi.ai").unwrap(), vfs.resolve("domains/api.hypi.ai").unwrap());
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").await.unwrap(), DomainOptions::new(123, "v1", false));
+    assert_eq!(vfs.read_domain_file("a.shop.hypi.ai").await.unwrap(), DomainOptions::new(456, "dev", true));
+    assert_eq!(vfs.read_domain_file("alias.hypi.ai").await.unwrap().service_id, 123);
+    assert!(matches!(vfs.read_domain_file("unknown.hypi.ai").await, Err(VfsErr::Domain(_))));
+
+    let files = vfs.resource_dir(123).await.unwrap();
+    assert_eq!(files, vfs.resolve("123/files").unwrap());
+    assert!(vfs.metadata(&files).await.unwrap().is_dir());
+    assert_eq!(vfs.resource_file(123, "logo.png").await.unwrap(), files.join("logo.png").unwrap());
+    write_file(&vfs, "123/files/logo.png", b"png").await;
+    let mut logo = vec![];
+    vfs.read_resource_file(123, "logo.png").await.unwrap().read_to_end(&mut logo).await.unwrap();
+    assert_eq!(logo, b"png");
+    assert!(vfs.read_resource_file(123, "missing.png").await.is_err());
+    assert!(vfs.resource_file(123, "../drafts/dev/schema.xml").await.is_err());
+
+    let tmp = vfs.tmp_dir(123).await.unwrap();
+    assert_eq!(tmp, vfs.resolve("123/.tmp").unwrap());
+    assert!(vfs.metadata(&tmp).await.unwrap().is_dir());
+
+    write_file(&vfs, "123/drafts/dev/ecma/main.js", b"import './lib/util.js'").await;
+    write_file(&vfs, "123/drafts/dev/ecma/lib/util.js", b"export {}").await;
+    let scripts: Vec<String> = vfs
+        .read_ecma(123, true, "dev")
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().rel.to_string_lossy().into_owned())
+        .collect()
+        .await;
+    assert_eq!(scripts, ["lib/util.js", "main.js"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_layout() {
+    layout(MemoryVfs::new("/services")).await;
+    let dir = tempfile::tempdir().unwrap();
+    layout(FilesystemVfs::new(dir.path().to_string_lossy().to_string())).await;
+    let dir = tempfile::tempdir().unwrap();
+    layout(BlockingVfs::new(Arc::new(FilesystemVfs::new(dir.path().to_string_lossy().to_string())))).await;
+    //the adapter resolves domains through the wrapped Vfs, so its cache is used
+    layout(BlockingVfs::new(Arc::new(CachingVfs::new(MemoryVfs::new("/services"))))).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_backends() {
+    round_trip(MemoryVfs::new("/services")).await;
+    let dir = tempfile::tempdir().unwrap();
+    round_trip(FilesystemVfs::new(dir.path().to_string_lossy().to_string())).await;
+    let dir = tempfile::tempdir().unwrap();
+    round_trip(FilesystemVfs::hardened(dir.path().to_string_lossy().to_string())).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blocking_adapter() {
+    let vfs = Arc::new(MemoryVfs::new("/services"));
+    round_trip(BlockingVfs::new(vfs.clone())).await;
+    let dir = tempfile::tempdir().unwrap();
+    round_trip(BlockingVfs::new(Arc::new(FilesystemVfs::new(dir.path().to_string_lossy().to_string())))).await;
+
+    //both see the same files
+    let adapter = BlockingVfs::new(vfs.clone());
+    vfs.insert("123/files/a.txt", "from sync").unwrap();
+    assert_eq!(adapter.read_to_end(adapter.resolve("123/files/a.txt").unwrap()).await.unwrap(), b"from sync");
+    assert_eq!(adapter.root(), Vfs::root(vfs.as_ref()));
+}
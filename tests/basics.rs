@@ -1,59 +1,72 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use rapid_fs::{FilesystemVfs, MemoryVfs};
-use rapid_fs::vfs::{BoundVfs, Vfs, VfsErr};
+use rapid_fs::hash::ContentHash;
+use rapid_fs::{CachingVfs, FilesystemVfs, MemoryVfs, VfsPath};
+use rapid_fs::diff::{ChangeKind, VersionRef};
+use rapid_fs::domain::{parse_domain_file, DomainEntry, DomainFile};
+use rapid_fs::ecma::{EcmaManifest, EcmaModuleInfo, ECMA_MANIFEST_FILE};
+use rapid_fs::validate::check_well_formed;
+use rapid_fs::version::{PublishOptions, VersionManifest, MANIFEST_FILE};
+use rapid_fs::watch::{WatchKind, Watcher};
+use rapid_fs::vfs::{
+    BoundVfs, DomainOptions, TlsHint, Vfs, VfsErr, VfsFile, VfsFileKind, VfsMetadata, VfsOpenOptions, VirtualReadDir,
+    MMAP_THRESHOLD,
+};
 
 pub fn resource_path(path: &str) -> String {
     format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), path)
 }
 
 pub fn read_str_resource(path: &str) -> String {
-    fs::read_to_string(resource_path(path)).expect(format!("Error reading test resource {}", path).as_str())
+    fs::read_to_string(resource_path(path)).unwrap_or_else(|_| panic!("Error reading test resource {}", path))
+}
+
+///Runs `$test` with `$vfs` bound to an `Arc` of each backend in turn: a [MemoryVfs], a [FilesystemVfs] in a new
+///temporary directory and, where it's supported, a hardened [FilesystemVfs] in another one
+macro_rules! for_each_backend {
+    (|$vfs:ident| $test:expr) => {{
+        {
+            let $vfs = Arc::new(MemoryVfs::new("/services"));
+            $test;
+        }
+        {
+            let dir = tempfile::tempdir().unwrap();
+            let $vfs = Arc::new(FilesystemVfs::new(dir.path().to_string_lossy().to_string()));
+            $test;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let dir = tempfile::tempdir().unwrap();
+            let $vfs = Arc::new(FilesystemVfs::hardened(dir.path().to_string_lossy().to_string()));
+            $test;
+        }
+    }};
 }
 
 #[test]
 fn memvfs() {
-    let vfs = MemoryVfs {
-        root: PathBuf::from("/private/path/to/services"), //cannot be empty, all paths must start with this
-        data: HashMap::from([
-            (
-                "/private/path/to/services/123/versions/v1/schema.xml".to_owned(),
-                ("schema.xml").to_owned(),
-            ),
-            (
-                "/private/path/to/services/123/versions/v1/pipeline_register.xml"
-                    .to_owned(),
-                ("pipeline_register.xml").to_owned(),
-            ),
-            (
-                "/private/path/to/services/123/versions/v1/pipeline2.xml".to_owned(),
-                ("pipeline2.xml").to_owned(),
-            ),
-            (
-                "/private/path/to/services/123/versions/v1/pipeline_billing_email.xml"
-                    .to_owned(),
-                ("pipeline_billing_email.xml").to_owned(),
-            ),
-            (
-                "/private/path/to/services/123/versions/v1/endpoint_subscription.xml"
-                    .to_owned(),
-                ("endpoint_subscription.xml").to_owned(),
-            ),
-            (
-                "/private/path/to/services/123/versions/v1/table_team_icon.xml".to_owned(),
-                ("table_team_icon.xml").to_owned(),
-            ),
-        ]),
-    };
+    let vfs = MemoryVfs::new("/private/path/to/services"); //cannot be empty, all paths must start with this
+    for name in [
+        "schema.xml",
+        "pipeline_register.xml",
+        "pipeline2.xml",
+        "pipeline_billing_email.xml",
+        "endpoint_subscription.xml",
+        "table_team_icon.xml",
+    ] {
+        vfs.insert(&format!("123/versions/v1/{}", name), name).unwrap();
+    }
     let mut schema = String::new();
-    vfs.read(PathBuf::from("/private/path/to/services/123/versions/v1/schema.xml")).unwrap().read_to_string(&mut schema).unwrap();
+    vfs.read(vfs.resolve("123/versions/v1/schema.xml").unwrap()).unwrap().read_to_string(&mut schema).unwrap();
     assert_eq!("schema.xml", schema);
 
-    match vfs.read(PathBuf::from("schema.xml")) {
+    match vfs.read(vfs.resolve("schema.xml").unwrap()) {
         Ok(_) => {
             panic!("Reading non-existent file produced a value")
         }
@@ -105,10 +118,1066 @@ fn fs_vfs() {
             panic!("Ahhh...this one should've worked! {}", e)
         }
     }
-    assert_eq!(vfs.resource_dir().unwrap(), PathBuf::from(resource_path("services/123/files")));
+    assert_eq!(vfs.resource_dir().unwrap().as_path(), Path::new(&resource_path("services/123/files")));
     assert!(vfs.resolve_resource(PathBuf::from("../domains/music.apps.hypi.ai")).is_err());
     assert_eq!(
         fs::read_to_string(vfs.resolve_resource("file1.txt".into()).unwrap()).unwrap(),
         "file1 content\n"
     );
 }
+
+#[test]
+fn resolve_by_component() {
+    let vfs = FilesystemVfs::new("/srv/services".to_owned());
+    assert_eq!(vfs.resolve("123/files/my..backup.txt").unwrap().as_path(), Path::new("/srv/services/123/files/my..backup.txt"));
+    assert_eq!(vfs.resolve("123/versions/v1./x").unwrap().as_path(), Path::new("/srv/services/123/versions/v1./x"));
+    assert!(matches!(vfs.resolve("123/../456"), Err(VfsErr::DotPathsNotSupported(_))));
+    assert!(matches!(vfs.resolve("./123"), Err(VfsErr::DotPathsNotSupported(_))));
+    assert!(matches!(vfs.resolve("/etc/passwd"), Err(VfsErr::AbsolutePathNotSupported(_))));
+    assert!(vfs.check_path(Path::new("/srv/services/123/files/a.txt")).is_ok());
+    assert!(vfs.check_path(Path::new("/srv/services2/123/files/a.txt")).is_err());
+    assert!(vfs.check_path(Path::new("/srv/services/123/../../services2")).is_err());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn hardened_fs_vfs_refuses_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("services");
+    fs::create_dir_all(root.join("123/files")).unwrap();
+    fs::create_dir_all(root.join("456/files")).unwrap();
+    fs::write(root.join("456/files/secret.txt"), "456's data").unwrap();
+    fs::write(root.join("123/files/own.txt"), "123's data").unwrap();
+    std::os::unix::fs::symlink(root.join("456/files/secret.txt"), root.join("123/files/link.txt")).unwrap();
+    std::os::unix::fs::symlink(root.join("456/files"), root.join("123/files/linked_dir")).unwrap();
+    fs::create_dir_all(root.join("123/versions/v1")).unwrap();
+    std::os::unix::fs::symlink(root.join("456/files"), root.join("123/versions/linked")).unwrap();
+
+    let vfs = FilesystemVfs::hardened(root.to_string_lossy().to_string());
+    let mut own = String::new();
+    vfs.read(vfs.resolve("123/files/own.txt").unwrap()).unwrap().read_to_string(&mut own).unwrap();
+    assert_eq!(own, "123's data");
+    assert!(matches!(vfs.read(vfs.resolve("123/files/link.txt").unwrap()), Err(VfsErr::PathEscapesRoot(_))));
+    assert!(matches!(vfs.metadata(&vfs.resolve("123/files/link.txt").unwrap()), Err(VfsErr::PathEscapesRoot(_))));
+    assert!(matches!(
+        vfs.read(vfs.resolve("123/files/linked_dir/secret.txt").unwrap()),
+        Err(VfsErr::PathEscapesRoot(_))
+    ));
+    assert!(matches!(vfs.read_dir(&vfs.resolve("123/files/linked_dir").unwrap()), Err(VfsErr::PathEscapesRoot(_))));
+    let mut listed: Vec<_> = vfs.read_dir(&vfs.resolve("123/files").unwrap()).unwrap().collect();
+    listed.sort();
+    assert_eq!(listed.len(), 3);
+    let copy = vfs.resolve("123/files/copy.txt").unwrap();
+    assert!(matches!(vfs.copy(&vfs.resolve("123/files/link.txt").unwrap(), &copy), Err(VfsErr::PathEscapesRoot(_))));
+    assert!(matches!(vfs.create_dir(&vfs.resolve("123/files/linked_dir/new").unwrap()), Err(VfsErr::PathEscapesRoot(_))));
+    assert!(!root.join("456/files/new").exists());
+    let versions: Vec<_> = vfs.list_versions(123).unwrap().into_iter().map(|v| v.name).collect();
+    assert_eq!(versions, ["v1"]);
+
+    //the same links are followed when not hardened
+    let vfs = FilesystemVfs::new(root.to_string_lossy().to_string());
+    assert!(vfs.read(vfs.resolve("123/files/link.txt").unwrap()).is_ok());
+}
+
+#[test]
+fn vfs_paths_carry_service() {
+    let vfs = FilesystemVfs::new(resource_path("services"));
+    assert_eq!(vfs.resolve("123/versions/v1/schema.xml").unwrap().service_id(), Some(123));
+    assert_eq!(vfs.domain_file("music.apps.hypi.ai").unwrap().service_id(), None);
+    let bound = BoundVfs::new(vfs.read_domain_file("music.apps.hypi.ai").unwrap(), Arc::new(vfs));
+    let file = bound.resolve_resource("./file1.txt".into()).unwrap();
+    assert_eq!(file.service_id(), Some(123));
+    assert!(file.join("../x").is_err());
+    let ecma: Vec<_> = bound.ecma_files().unwrap().map(|v| v.unwrap()).collect();
+    assert_eq!(ecma.len(), 1);
+    assert_eq!(ecma[0].rel, PathBuf::from("file1.js"));
+    assert_eq!(ecma[0].path.service_id(), Some(123));
+}
+
+#[test]
+fn memvfs_writes_are_shared() {
+    let vfs = MemoryVfs::new("/services");
+    let path = vfs.resource_file(123, "data.bin").unwrap();
+    let mut opts = VfsOpenOptions::new();
+    opts.read(true).write(true).create_new(true);
+    let mut file = vfs.open_with(path.clone(), opts.clone()).unwrap();
+    file.write_all(&[0, 159, 146, 150]).unwrap();
+    //nothing is visible until the file is flushed
+    assert_eq!(read_all(&vfs, &path), Vec::<u8>::new());
+    file.seek(SeekFrom::Start(1)).unwrap();
+    file.write_all(&[1]).unwrap();
+    assert_eq!(file.seek(SeekFrom::End(-1)).unwrap(), 3);
+    assert!(file.seek(SeekFrom::Current(-4)).is_err());
+    file.flush().unwrap();
+    //a clone of the vfs sees the same files
+    assert_eq!(read_all(&vfs.clone(), &path), vec![0, 1, 146, 150]);
+    assert!(vfs.open_with(path.clone(), opts).is_err());
+
+    let mut opts = VfsOpenOptions::new();
+    opts.append(true);
+    let mut file = vfs.open_with(path.clone(), opts).unwrap();
+    file.write_all(b"!").unwrap();
+    drop(file);
+    assert_eq!(read_all(&vfs, &path), vec![0, 1, 146, 150, b'!']);
+
+    //seeking or writing past the largest possible file fails like a real file instead of panicking
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true);
+    let mut file = vfs.open_with(path.clone(), opts).unwrap();
+    file.seek(SeekFrom::Start(u64::MAX)).unwrap();
+    assert_eq!(file.write(b"x").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    file.seek(SeekFrom::Start(1 << 62)).unwrap();
+    assert_eq!(file.write(b"x").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    drop(file);
+    assert_eq!(read_all(&vfs, &path), vec![0, 1, 146, 150, b'!']);
+
+    let mut opts = VfsOpenOptions::new();
+    opts.read(true);
+    let mut file = vfs.open_with(path.clone(), opts).unwrap();
+    assert!(file.write_all(b"nope").is_err());
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).truncate(true);
+    drop(vfs.open_with(path.clone(), opts).unwrap());
+    assert_eq!(read_all(&vfs, &path), Vec::<u8>::new());
+
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true);
+    assert!(matches!(
+        vfs.open_with(vfs.resolve("123/files/missing.txt").unwrap(), opts),
+        Err(VfsErr::FileNotFound(_))
+    ));
+}
+
+fn read_all<F: Vfs>(vfs: &F, path: &VfsPath) -> Vec<u8> {
+    let mut data = vec![];
+    vfs.read(path.clone()).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn dirs_are_created_by_the_backend() {
+    let vfs = MemoryVfs::new("/rapid-fs-test-does-not-exist");
+    let files = vfs.resource_dir(123).unwrap();
+    assert!(!files.as_path().exists());
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create(true);
+    drop(vfs.open_with(files.join("a.txt").unwrap(), opts.clone()).unwrap());
+    assert!(vfs.remove_dir(&files).is_err());
+    assert!(vfs.open_with(vfs.resolve("456/files/a.txt").unwrap(), opts.clone()).is_err());
+    vfs.remove_dir(&vfs.plugins_dir(123).unwrap()).unwrap();
+
+    let vfs = vfs.with_lazy_dirs(true);
+    drop(vfs.open_with(vfs.resolve("456/files/a.txt").unwrap(), opts.clone()).unwrap());
+
+    let dir = tempfile::tempdir().unwrap();
+    let vfs = FilesystemVfs::new(dir.path().to_string_lossy().to_string()).with_lazy_dirs(true);
+    let files = vfs.resource_dir(123).unwrap();
+    assert!(!files.as_path().exists());
+    drop(vfs.open_with(files.join("a.txt").unwrap(), opts.clone()).unwrap());
+    assert!(files.as_path().is_dir());
+
+    let vfs = FilesystemVfs::hardened(dir.path().to_string_lossy().to_string());
+    let plugins = vfs.plugins_dir(123).unwrap();
+    assert!(plugins.as_path().is_dir());
+    vfs.remove_dir(&plugins).unwrap();
+    assert!(!plugins.as_path().exists());
+}
+
+fn upload_lifecycle<F: Vfs>(vfs: Arc<F>) {
+    let bound = BoundVfs::new(DomainOptions::new(123, "v1", false), vfs.clone());
+    let tmp_dir = vfs.tmp_dir(123).unwrap();
+
+    let mut upload = bound.create_temp().unwrap();
+    assert!(upload.path().as_path().starts_with(tmp_dir.as_path()));
+    upload.write_all(b"uploaded").unwrap();
+    assert_eq!(bound.save_to(&mut upload, Some("saved.txt".to_owned())).unwrap(), "saved.txt");
+    drop(upload);
+    assert_eq!(read_all(vfs.as_ref(), &bound.resolve_resource("saved.txt".into()).unwrap()), b"uploaded");
+
+    let mut upload = bound.create_temp().unwrap();
+    upload.write_all(b"discarded").unwrap();
+    let path = upload.path();
+    bound.discard(&upload).unwrap();
+    drop(upload);
+    assert!(vfs.read(path).is_err());
+
+    let upload = bound.create_temp().unwrap();
+    let path = upload.path();
+    assert!(vfs.read(path.clone()).is_ok());
+    drop(upload);
+    assert!(vfs.read(path).is_err());
+
+    let other = BoundVfs::new(DomainOptions::new(456, "v1", false), vfs.clone());
+    let mut upload = bound.create_temp().unwrap();
+    assert!(matches!(other.save_to(&mut upload, None), Err(VfsErr::ServiceMismatch(_))));
+}
+
+#[test]
+fn temp_uploads() {
+    for_each_backend!(|vfs| upload_lifecycle(vfs));
+}
+
+fn atomic_writes<F: Vfs>(vfs: Arc<F>) {
+    let bound = BoundVfs::new(DomainOptions::new(123, "dev", true), vfs.clone());
+    let schema = vfs.schema_file(123, true, "dev", "schema.xml").unwrap();
+    vfs.create_dir_all(&vfs.schema_file(123, true, "dev", "").unwrap()).unwrap();
+    vfs.write_atomic(schema.clone(), b"<document/>").unwrap();
+    assert_eq!(bound.read_schema_file("schema.xml").unwrap(), "<document/>");
+
+    let draft = vfs.version_dir(123, true, "dev").unwrap();
+    let mut abandoned = bound.schema_writer("schema.xml").unwrap();
+    abandoned.write_all(b"<docu").unwrap();
+    //the temp file is next to the target but hidden from walks
+    assert_eq!(vfs.read_dir(&draft).unwrap().count(), 2);
+    let walked: Vec<_> = vfs.dir_stream(draft.clone()).unwrap().map(|entry| entry.unwrap().path).collect();
+    assert_eq!(walked, std::slice::from_ref(&schema));
+    drop(abandoned);
+    assert_eq!(bound.read_schema_file("schema.xml").unwrap(), "<document/>");
+    assert_eq!(vfs.read_dir(&draft).unwrap().collect::<Vec<_>>(), std::slice::from_ref(&schema));
+
+    let previous = ContentHash::of(b"<document/>");
+    let mut first = bound.schema_writer("schema.xml").unwrap().expect_previous(Some(previous));
+    let mut second = bound.schema_writer("schema.xml").unwrap().expect_previous(Some(previous));
+    first.write_all(b"<document>first</document>").unwrap();
+    second.write_all(b"<document>second</document>").unwrap();
+    first.commit().unwrap();
+    assert!(matches!(second.commit(), Err(VfsErr::Conflict(_))));
+    assert_eq!(bound.read_schema_file("schema.xml").unwrap(), "<document>first</document>");
+
+    let mut new_file = bound.resource_writer("new.txt".into()).unwrap().expect_previous(None);
+    new_file.write_all(b"new").unwrap();
+    new_file.commit().unwrap();
+    assert_eq!(read_all(vfs.as_ref(), &bound.resolve_resource("new.txt".into()).unwrap()), b"new");
+    assert_eq!(vfs.read_dir(&draft).unwrap().collect::<Vec<_>>(), [schema]);
+    let files = vfs.resource_dir(123).unwrap();
+    assert_eq!(vfs.read_dir(&files).unwrap().collect::<Vec<_>>(), [files.join("new.txt").unwrap()]);
+}
+
+#[test]
+fn atomic_writer() {
+    for_each_backend!(|vfs| atomic_writes(vfs));
+}
+
+fn write_file<F: Vfs>(vfs: &F, path: &str, data: &[u8]) {
+    let parent = Path::new(path).parent().unwrap().to_str().unwrap();
+    vfs.create_dir_all(&vfs.resolve(parent).unwrap()).unwrap();
+    vfs.write_atomic(vfs.resolve(path).unwrap(), data).unwrap();
+}
+
+fn publishing<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export {}");
+    write_file(vfs.as_ref(), "domains/dev.hypi.ai", br#"{"service_id":123,"version":"dev","is_draft":true}"#);
+    write_file(vfs.as_ref(), "domains/other.hypi.ai", br#"{"service_id":456,"version":"dev","is_draft":true}"#);
+
+    let published = vfs.publish_draft(123, "dev", "v2").unwrap();
+    assert_eq!(published.dir, vfs.version_dir(123, false, "v2").unwrap());
+    assert_eq!(published.domains, vec!["dev.hypi.ai".to_owned()]);
+    assert_eq!(published.manifest.files.len(), 2);
+    assert_eq!(published.manifest.files["ecma/lib/util.js"], ContentHash::of(b"export {}"));
+    assert_eq!(vfs.read_schema_file(123, false, "v2", "schema.xml").unwrap(), "<document/>");
+    assert_eq!(vfs.read_schema_file(123, false, "v2", "ecma/lib/util.js").unwrap(), "export {}");
+    let manifest: VersionManifest =
+        serde_json::from_str(&vfs.read_schema_file(123, false, "v2", MANIFEST_FILE).unwrap()).unwrap();
+    assert_eq!(manifest, published.manifest);
+    //the draft is left alone
+    assert_eq!(vfs.read_schema_file(123, true, "dev", "schema.xml").unwrap(), "<document/>");
+
+    let domain = vfs.read_domain_file("dev.hypi.ai").unwrap();
+    assert_eq!(domain, DomainOptions::new(123, "v2", false));
+    assert!(vfs.read_domain_file("other.hypi.ai").unwrap().is_draft);
+
+    assert!(matches!(vfs.publish_draft(123, "dev", "v2"), Err(VfsErr::VersionExists(_))));
+    assert!(matches!(vfs.publish_draft(123, "dev", "v3/nested"), Err(VfsErr::InvalidVersionName(_))));
+    write_file(vfs.as_ref(), "123/drafts/empty/readme.txt", b"no schema");
+    assert!(matches!(vfs.publish_draft(123, "empty", "v3"), Err(VfsErr::SchemaFileNotFound(_))));
+    assert!(vfs.read_dir(&vfs.version_dir(123, false, "v3").unwrap()).is_err());
+
+    //a draft made from a published version carries its generated manifests, they're regenerated not copied
+    let v2 = vfs.version_dir(123, false, "v2").unwrap();
+    let redo = vfs.version_dir(123, true, "redo").unwrap();
+    for name in ["schema.xml", MANIFEST_FILE, ECMA_MANIFEST_FILE] {
+        write_file(vfs.as_ref(), &format!("123/drafts/redo/{}", name), &read_all(vfs.as_ref(), &v2.join(name).unwrap()));
+    }
+    let republished = vfs.publish_draft(123, "redo", "v4").unwrap();
+    assert_eq!(republished.manifest.files.keys().collect::<Vec<_>>(), ["schema.xml"]);
+    assert_eq!(vfs.read_dir(&redo).unwrap().count(), 3);
+    //staging happens next to the versions and nothing of it is left behind
+    let versions = vfs.resolve("123/versions").unwrap();
+    let mut listed: Vec<_> = vfs.read_dir(&versions).unwrap().collect();
+    listed.sort();
+    assert_eq!(listed, [v2.clone(), versions.join("v4").unwrap()]);
+    //an empty directory in the way is someone else's version, not replaced
+    vfs.create_dir(&versions.join("v5").unwrap()).unwrap();
+    assert!(matches!(vfs.publish_draft(123, "redo", "v5"), Err(VfsErr::VersionExists(_))));
+    assert_eq!(vfs.read_dir(&versions.join("v5").unwrap()).unwrap().count(), 0);
+}
+
+#[test]
+fn publish_draft() {
+    for_each_backend!(|vfs| publishing(vfs));
+}
+
+fn version_admin<F: Vfs>(vfs: Arc<F>) {
+    assert!(vfs.list_versions(123).unwrap().is_empty());
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export {}");
+    write_file(vfs.as_ref(), "123/versions/v1/table.xml", b"<table/>");
+    write_file(vfs.as_ref(), "domains/api.hypi.ai", br#"{"service_id":123,"version":"v1","is_draft":false}"#);
+
+    let versions = vfs.list_versions(123).unwrap();
+    let summary: Vec<_> = versions
+        .iter()
+        .map(|v| (v.name.as_str(), v.is_draft, v.file_count, v.total_size, v.has_schema))
+        .collect();
+    assert_eq!(summary, vec![("dev", true, 2, 20, true), ("v1", false, 1, 8, false)]);
+    //not every filesystem records when a directory was created
+    assert!(versions.iter().all(|v| v.modified.is_some()));
+
+    assert!(matches!(vfs.delete_version(123, false, "v1"), Err(VfsErr::VersionInUse(domains)) if domains == "api.hypi.ai"));
+    vfs.delete_version(123, true, "dev").unwrap();
+    assert!(matches!(vfs.delete_version(123, true, "dev"), Err(VfsErr::VersionNotFound(v)) if v == "dev"));
+    assert!(vfs.read_schema_file(123, true, "dev", "schema.xml").is_err());
+    let names: Vec<_> = vfs.list_versions(123).unwrap().into_iter().map(|v| v.name).collect();
+    assert_eq!(names, vec!["v1".to_owned()]);
+}
+
+#[test]
+fn list_and_delete_versions() {
+    for_each_backend!(|vfs| version_admin(vfs));
+}
+
+#[test]
+fn diff_versions() {
+    let vfs = MemoryVfs::new("/services");
+    write_file(&vfs, "123/versions/v3/schema.xml", b"<document>\n<table name=\"a\"/>\n</document>\n");
+    write_file(&vfs, "123/versions/v3/ecma/old.js", b"export const a = 1;\n");
+    write_file(&vfs, "123/versions/v3/logo.png", b"png");
+    write_file(&vfs, "123/drafts/dev/schema.xml", b"<document>\n<table name=\"b\"/>\n</document>\n");
+    write_file(&vfs, "123/drafts/dev/ecma/new.js", b"export const b = 2;\n");
+    write_file(&vfs, "123/drafts/dev/logo.png", b"png");
+
+    let diff = vfs.diff_versions(123, &VersionRef::version("v3"), &VersionRef::draft("dev")).unwrap();
+    let changes: Vec<_> = diff.changes.iter().map(|c| (c.path.as_str(), c.kind)).collect();
+    assert_eq!(
+        changes,
+        vec![
+            ("ecma/new.js", ChangeKind::Added),
+            ("ecma/old.js", ChangeKind::Removed),
+            ("schema.xml", ChangeKind::Modified),
+        ]
+    );
+    let schema = diff.modified().next().unwrap();
+    assert_eq!(schema.old, Some(ContentHash::of(b"<document>\n<table name=\"a\"/>\n</document>\n")));
+    let text = schema.diff.as_deref().unwrap();
+    assert!(text.starts_with("--- v3/schema.xml\n+++ dev/schema.xml\n"), "{}", text);
+    assert!(text.contains("-<table name=\"a\"/>\n+<table name=\"b\"/>\n"), "{}", text);
+    assert!(diff.added().next().unwrap().diff.as_deref().unwrap().contains("+export const b = 2;"));
+
+    //the manifest written on publish is not part of the diff
+    vfs.publish_draft(123, "dev", "v4").unwrap();
+    assert!(vfs.diff_versions(123, &VersionRef::draft("dev"), &VersionRef::version("v4")).unwrap().is_empty());
+    let missing = vfs.diff_versions(123, &VersionRef::version("v3"), &VersionRef::version("v9"));
+    assert!(matches!(missing, Err(VfsErr::VersionNotFound(v)) if v == "v9"));
+}
+
+fn domain_admin<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/versions/v1/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", b"<document/>");
+
+    vfs.write_domain_file("api.example.com", &DomainOptions::new(123, "v1", false)).unwrap();
+    assert_eq!(vfs.read_domain_file("api.example.com").unwrap(), DomainOptions::new(123, "v1", false));
+    for domain in ["", "-bad.example.com", "a..b", "under_score.com", "../escape", "a/b"] {
+        let res = vfs.write_domain_file(domain, &DomainOptions::new(123, "v1", false));
+        assert!(matches!(res, Err(VfsErr::InvalidDomain(_))), "{}", domain);
+    }
+    let res = vfs.write_domain_file("api.example.com", &DomainOptions::new(123, "v9", false));
+    assert!(matches!(res, Err(VfsErr::VersionNotFound(_))));
+    let res = vfs.write_domain_file("api.example.com", &DomainOptions::new(456, "v1", false));
+    assert!(matches!(res, Err(VfsErr::VersionNotFound(_))));
+
+    assert_eq!(vfs.rebind_domain("api.example.com", "dev", true).unwrap(), DomainOptions::new(123, "dev", true));
+    assert_eq!(vfs.read_domain_file("api.example.com").unwrap(), DomainOptions::new(123, "dev", true));
+    assert!(matches!(vfs.rebind_domain("api.example.com", "v9", false), Err(VfsErr::VersionNotFound(_))));
+    assert!(matches!(vfs.rebind_domain("missing.example.com", "v1", false), Err(VfsErr::Domain(_))));
+
+    vfs.delete_domain_file("api.example.com").unwrap();
+    assert!(vfs.read_domain_file("api.example.com").is_err());
+    assert!(matches!(vfs.delete_domain_file("api.example.com"), Err(VfsErr::Domain(_))));
+}
+
+#[test]
+fn domain_files() {
+    for_each_backend!(|vfs| domain_admin(vfs));
+
+    //is_draft defaults to false when it's left out
+    let vfs = FilesystemVfs::new(env!("CARGO_MANIFEST_DIR").to_owned());
+    let domain = vfs.read_domain_file("api.hypi.ai").unwrap();
+    assert_eq!(domain, DomainOptions::new(123, "v1", false));
+}
+
+fn indexed_domains<F: Vfs>(vfs: Arc<F>) {
+    assert!(vfs.domains_for_service(123).unwrap().is_empty());
+    write_file(vfs.as_ref(), "123/versions/v1/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "domains/b.hypi.ai", br#"{"service_id":123,"version":"v1","is_draft":false}"#);
+    write_file(vfs.as_ref(), "domains/a.hypi.ai", br#"{"service_id":123,"version":"dev","is_draft":true}"#);
+    write_file(vfs.as_ref(), "domains/c.hypi.ai", br#"{"service_id":456,"version":"v1","is_draft":false}"#);
+    write_file(vfs.as_ref(), "domains/broken.hypi.ai", b"not json");
+
+    assert_eq!(vfs.domains_for_service(123).unwrap(), vec!["a.hypi.ai", "b.hypi.ai"]);
+    assert_eq!(vfs.domains_for_version(123, "v1", false).unwrap(), vec!["b.hypi.ai"]);
+    assert_eq!(vfs.domains_for_version(123, "v1", true).unwrap(), Vec::<String>::new());
+
+    //changes made behind the index's back are picked up on the next lookup
+    write_file(vfs.as_ref(), "domains/c.hypi.ai", br#"{"service_id":123,"version":"v1","is_draft":false}"#);
+    vfs.remove_file(&vfs.domain_file("a.hypi.ai").unwrap()).unwrap();
+    assert_eq!(vfs.domains_for_version(123, "v1", false).unwrap(), vec!["b.hypi.ai", "c.hypi.ai"]);
+    assert_eq!(vfs.domains_for_service(456).unwrap(), Vec::<String>::new());
+
+    vfs.rebind_domain("b.hypi.ai", "v1", false).unwrap();
+    vfs.delete_domain_file("c.hypi.ai").unwrap();
+    assert_eq!(vfs.domains_for_service(123).unwrap(), vec!["b.hypi.ai"]);
+    let index = vfs.domain_index().unwrap();
+    assert_eq!(index.get("b.hypi.ai"), Some(DomainOptions::new(123, "v1", false)));
+    assert_eq!(index.get("broken.hypi.ai"), None);
+}
+
+fn wildcards_and_aliases<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/versions/v1/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "456/versions/v2/schema.xml", b"<document/>");
+    vfs.write_domain_file("*.customer.example.com", &DomainOptions::new(123, "v1", false)).unwrap();
+    vfs.write_domain_file("*.example.com", &DomainOptions::new(456, "v2", false)).unwrap();
+    vfs.write_domain_file("vip.customer.example.com", &DomainOptions::new(456, "v2", false)).unwrap();
+
+    assert_eq!(vfs.read_domain_file("a.customer.example.com").unwrap().service_id, 123);
+    assert_eq!(vfs.read_domain_file("a.b.customer.example.com").unwrap().service_id, 123);
+    assert_eq!(vfs.read_domain_file("vip.customer.example.com").unwrap().service_id, 456);
+    assert_eq!(vfs.read_domain_file("other.example.com").unwrap().service_id, 456);
+    assert!(matches!(vfs.read_domain_file("example.org"), Err(VfsErr::Domain(_))));
+
+    vfs.write_domain_alias("shop.example.org", "a.customer.example.com").unwrap();
+    vfs.write_domain_alias("www.shop.example.org", "shop.example.org").unwrap();
+    assert_eq!(vfs.read_domain_file("www.shop.example.org").unwrap(), DomainOptions::new(123, "v1", false));
+    assert!(matches!(vfs.rebind_domain("shop.example.org", "v1", false), Err(VfsErr::DomainIsAlias(_))));
+    assert_eq!(
+        vfs.domains_for_service(123).unwrap(),
+        vec!["*.customer.example.com", "shop.example.org", "www.shop.example.org"]
+    );
+
+    //an alias closing a loop is refused, one written behind our back is detected when it's read
+    assert!(matches!(vfs.write_domain_alias("shop.example.org", "www.shop.example.org"), Err(VfsErr::DomainLoop(_))));
+    assert!(matches!(vfs.write_domain_alias("x.example.org", "missing.example.org"), Err(VfsErr::Domain(_))));
+    write_file(vfs.as_ref(), "domains/shop.example.org", br#"{"alias_of":"www.shop.example.org"}"#);
+    assert!(matches!(vfs.read_domain_file("www.shop.example.org"), Err(VfsErr::DomainLoop(_))));
+    assert_eq!(vfs.domains_for_service(123).unwrap(), vec!["*.customer.example.com"]);
+}
+
+#[test]
+fn domain_wildcards_and_aliases() {
+    for_each_backend!(|vfs| wildcards_and_aliases(vfs));
+}
+
+#[test]
+fn domain_file_formats() {
+    let legacy = parse_domain_file(b"123\nv1\n").unwrap();
+    assert_eq!(legacy, DomainFile { format: 0, entry: DomainEntry::Options(DomainOptions::new(123, "v1", false)) });
+    let legacy = parse_domain_file(b"  123\r\ndev\r\ndraft\r\n").unwrap();
+    assert_eq!(legacy.entry, DomainEntry::Options(DomainOptions::new(123, "dev", true)));
+    assert!(matches!(parse_domain_file(b"abc\nv1"), Err(VfsErr::InvalidDomainFile(_))));
+    assert!(matches!(parse_domain_file(b"123"), Err(VfsErr::InvalidDomainFile(_))));
+
+    let full = parse_domain_file(
+        br#"{"format":1,"service_id":123,"version":"v1","tls":"redirect","maintenance":true,"headers":{"x-env":"prod"}}"#,
+    )
+    .unwrap();
+    let mut options = DomainOptions::new(123, "v1", false);
+    options.tls = Some(TlsHint::Redirect);
+    options.maintenance = true;
+    options.headers.insert("x-env".to_owned(), "prod".to_owned());
+    assert_eq!(full, DomainFile::new(DomainEntry::Options(options.clone())));
+    assert_eq!(parse_domain_file(&full.to_json().unwrap()).unwrap(), full);
+    let alias = DomainFile::new(DomainEntry::Alias { alias_of: "api.hypi.ai".to_owned() });
+    assert_eq!(parse_domain_file(&alias.to_json().unwrap()).unwrap(), alias);
+    //written by a newer version of the crate
+    let newer = br#"{"format":2,"service_id":123,"version":"v1","routes":[]}"#;
+    assert!(matches!(parse_domain_file(newer), Err(VfsErr::InvalidDomainFile(e)) if e == "unsupported format 2"));
+
+    let vfs = MemoryVfs::new("/services");
+    write_file(&vfs, "123/versions/v1/schema.xml", b"<document/>");
+    write_file(&vfs, "domains/lines.hypi.ai", b"123\nv1\n");
+    write_file(&vfs, "domains/json.hypi.ai", br#"{"service_id":123,"version":"v1"}"#);
+    write_file(&vfs, "domains/newer.hypi.ai", newer);
+    vfs.write_domain_file("current.hypi.ai", &options).unwrap();
+    assert_eq!(vfs.read_domain_file("lines.hypi.ai").unwrap(), DomainOptions::new(123, "v1", false));
+    assert!(matches!(vfs.read_domain_file("newer.hypi.ai"), Err(VfsErr::InvalidDomainFile(_))));
+    assert_eq!(vfs.migrate_domain_files().unwrap(), vec!["json.hypi.ai", "lines.hypi.ai"]);
+    assert!(vfs.migrate_domain_files().unwrap().is_empty());
+    let migrated = read_all(&vfs, &vfs.domain_file("lines.hypi.ai").unwrap());
+    assert_eq!(parse_domain_file(&migrated).unwrap(), DomainFile::new(DomainEntry::Options(DomainOptions::new(123, "v1", false))));
+    //rebinding keeps the optional fields
+    assert_eq!(vfs.rebind_domain("current.hypi.ai", "v1", false).unwrap(), options);
+}
+
+#[test]
+fn caching_domain_lookups() {
+    let backend = MemoryVfs::new("/services");
+    write_file(&backend, "123/versions/v1/schema.xml", b"<document/>");
+    write_file(&backend, "123/versions/v2/schema.xml", b"<document/>");
+    backend.insert("domains/api.hypi.ai", r#"{"service_id":123,"version":"v1"}"#).unwrap();
+
+    //without mtime checks entries are trusted until they expire or are invalidated
+    let vfs = CachingVfs::new(backend.clone()).with_mtime_checks(false).with_negative_ttl(Duration::from_secs(60));
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").unwrap().version, "v1");
+    backend.insert("domains/api.hypi.ai", r#"{"service_id":123,"version":"v2"}"#).unwrap();
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").unwrap().version, "v1");
+    vfs.invalidate_domain("api.hypi.ai");
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").unwrap().version, "v2");
+
+    assert!(matches!(vfs.read_domain_file("new.hypi.ai"), Err(VfsErr::Domain(_))));
+    backend.insert("domains/new.hypi.ai", r#"{"service_id":123,"version":"v1"}"#).unwrap();
+    assert!(matches!(vfs.read_domain_file("new.hypi.ai"), Err(VfsErr::Domain(_))));
+    //writes through the cache clear it
+    vfs.rebind_domain("api.hypi.ai", "v1", false).unwrap();
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").unwrap().version, "v1");
+    assert_eq!(vfs.read_domain_file("new.hypi.ai").unwrap().version, "v1");
+
+    //with them, a change to any file a known domain was resolved from is noticed, misses last until they expire
+    let vfs = CachingVfs::new(backend.clone()).with_negative_ttl(Duration::from_secs(60));
+    assert!(matches!(vfs.read_domain_file("a.shop.hypi.ai"), Err(VfsErr::Domain(_))));
+    backend.insert("domains/*.shop.hypi.ai", r#"{"service_id":123,"version":"v1"}"#).unwrap();
+    assert!(matches!(vfs.read_domain_file("a.shop.hypi.ai"), Err(VfsErr::Domain(_))));
+    vfs.invalidate_domain("a.shop.hypi.ai");
+    assert_eq!(vfs.read_domain_file("a.shop.hypi.ai").unwrap().version, "v1");
+    backend.insert("domains/a.shop.hypi.ai", r#"{"service_id":123,"version":"v2"}"#).unwrap();
+    assert_eq!(vfs.read_domain_file("a.shop.hypi.ai").unwrap().version, "v2");
+
+    let vfs = CachingVfs::new(backend.clone()).with_ttl(Duration::ZERO);
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").unwrap().version, "v1");
+    backend.insert("domains/api.hypi.ai", r#"{"service_id":123,"version":"v2"}"#).unwrap();
+    assert_eq!(vfs.read_domain_file("api.hypi.ai").unwrap().version, "v2");
+}
+
+///A [MemoryVfs] which counts the calls that read or stat something
+#[derive(Clone)]
+struct CountingVfs {
+    inner: MemoryVfs,
+    reads: Arc<AtomicUsize>,
+}
+
+impl CountingVfs {
+    fn count(&self) -> usize {
+        self.reads.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+impl Vfs for CountingVfs {
+    fn root(&self) -> &PathBuf {
+        self.inner.root()
+    }
+    fn create_dir_all(&self, dir: &VfsPath) -> rapid_fs::vfs::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+    fn create_dir(&self, dir: &VfsPath) -> rapid_fs::vfs::Result<()> {
+        self.inner.create_dir(dir)
+    }
+    fn remove_dir(&self, dir: &VfsPath) -> rapid_fs::vfs::Result<()> {
+        self.inner.remove_dir(dir)
+    }
+    fn remove_dir_all(&self, dir: &VfsPath) -> rapid_fs::vfs::Result<()> {
+        self.inner.remove_dir_all(dir)
+    }
+    fn rename(&self, from: &VfsPath, to: &VfsPath) -> rapid_fs::vfs::Result<()> {
+        self.inner.rename(from, to)
+    }
+    fn remove_file(&self, file: &VfsPath) -> rapid_fs::vfs::Result<()> {
+        self.inner.remove_file(file)
+    }
+    fn metadata(&self, path: &VfsPath) -> rapid_fs::vfs::Result<VfsMetadata> {
+        self.count();
+        self.inner.metadata(path)
+    }
+    fn read(&self, file: VfsPath) -> rapid_fs::vfs::Result<Box<dyn Read + '_>> {
+        self.count();
+        self.inner.read(file)
+    }
+    fn open_with(&self, file: VfsPath, opts: VfsOpenOptions) -> rapid_fs::vfs::Result<Box<dyn VfsFile>> {
+        self.count();
+        self.inner.open_with(file, opts)
+    }
+    fn read_dir(&self, dir: &VfsPath) -> rapid_fs::vfs::Result<VirtualReadDir> {
+        self.count();
+        self.inner.read_dir(dir)
+    }
+}
+
+#[test]
+fn cached_misses_skip_the_backend() {
+    let backend = CountingVfs {
+        inner: MemoryVfs::new("/services"),
+        reads: Arc::new(AtomicUsize::new(0)),
+    };
+    let reads = backend.reads.clone();
+    let vfs = CachingVfs::new(backend);
+    assert!(matches!(vfs.read_domain_file("a.b.unknown.hypi.ai"), Err(VfsErr::Domain(_))));
+    let first = reads.load(Ordering::SeqCst);
+    assert!(first > 0);
+    for _ in 0..100 {
+        assert!(matches!(vfs.read_domain_file("a.b.unknown.hypi.ai"), Err(VfsErr::Domain(_))));
+    }
+    assert_eq!(reads.load(Ordering::SeqCst), first);
+}
+
+#[test]
+fn domain_index() {
+    for_each_backend!(|vfs| indexed_domains(vfs));
+}
+
+#[test]
+fn schema_bundles() {
+    let vfs = Arc::new(FilesystemVfs::new(resource_path("services")));
+    let bound = BoundVfs::new(vfs.read_domain_file("music.apps.hypi.ai").unwrap(), vfs.clone());
+    let bundle = bound.load_schema_bundle().unwrap();
+    assert_eq!(bundle.keys().collect::<Vec<_>>(), vec!["schema.xml", "pipeline_register.xml"]);
+    assert_eq!(bundle["pipeline_register.xml"], read_str_resource("services/123/versions/v1/pipeline_register.xml"));
+
+    let vfs = MemoryVfs::new("/services");
+    let schema = |name: &str, xml: &str| vfs.insert(&format!("123/drafts/dev/{}", name), xml.to_owned()).unwrap();
+    schema("schema.xml", r#"<document><apis><pipeline import="a.xml"/><pipeline import="b.xml"/></apis></document>"#);
+    schema("a.xml", r#"<pipeline><step import="shared/c.xml"/></pipeline>"#);
+    schema("b.xml", r#"<pipeline><step import="shared/c.xml"/></pipeline>"#);
+    schema("shared/c.xml", r#"<step/>"#);
+    let bundle = vfs.load_schema_bundle(123, true, "dev").unwrap();
+    assert_eq!(bundle.keys().collect::<Vec<_>>(), vec!["schema.xml", "a.xml", "shared/c.xml", "b.xml"]);
+
+    schema("shared/c.xml", r#"<step import="b.xml"/>"#);
+    match vfs.load_schema_bundle(123, true, "dev") {
+        Err(VfsErr::ImportCycle(cycle)) => assert_eq!(cycle, "schema.xml -> a.xml -> shared/c.xml -> b.xml -> shared/c.xml"),
+        other => panic!("Expected an import cycle, got {:?}", other),
+    }
+    schema("shared/c.xml", r#"<step import="missing.xml"/>"#);
+    match vfs.load_schema_bundle(123, true, "dev") {
+        Err(VfsErr::MissingImport { importer, import }) => assert_eq!((importer.as_str(), import.as_str()), ("shared/c.xml", "missing.xml")),
+        other => panic!("Expected a missing import, got {:?}", other),
+    }
+    schema("shared/c.xml", r#"<step import="../../versions/v1/schema.xml"/>"#);
+    assert!(matches!(vfs.load_schema_bundle(123, true, "dev"), Err(VfsErr::DotPathsNotSupported(_))));
+    assert!(matches!(vfs.load_schema_bundle(123, true, "missing"), Err(VfsErr::SchemaFileNotFound(_))));
+
+    //imports are keyed by the file they name, however it's spelled
+    schema("schema.xml", r#"<document><pipeline import="./a.xml"/><pipeline import="a.xml"/></document>"#);
+    schema("a.xml", r#"<pipeline><step import="shared//c.xml"/></pipeline>"#);
+    schema("shared/c.xml", r#"<step/>"#);
+    let bundle = vfs.load_schema_bundle(123, true, "dev").unwrap();
+    assert_eq!(bundle.keys().collect::<Vec<_>>(), vec!["schema.xml", "a.xml", "shared/c.xml"]);
+    schema("a.xml", r#"<pipeline><step import="./a.xml"/></pipeline>"#);
+    match vfs.load_schema_bundle(123, true, "dev") {
+        Err(VfsErr::ImportCycle(cycle)) => assert_eq!(cycle, "schema.xml -> a.xml -> a.xml"),
+        other => panic!("Expected an import cycle, got {:?}", other),
+    }
+
+    //an import through a file is missing like any other, whichever error the backend reports for it
+    for_each_backend!(|vfs| {
+        let xml = br#"<document><pipeline import="a.xml/b.xml"/></document>"#;
+        write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", xml);
+        write_file(vfs.as_ref(), "123/drafts/dev/a.xml", b"<pipeline/>");
+        assert!(matches!(vfs.load_schema_bundle(123, true, "dev"), Err(VfsErr::MissingImport { .. })));
+    });
+}
+
+#[test]
+fn schema_validation() {
+    match check_well_formed("schema.xml", &read_str_resource("services/123/versions/v1/schema.xml")) {
+        Err(VfsErr::InvalidSchema { file, line, column, .. }) => assert_eq!((file.as_str(), line, column), ("schema.xml", 6, 9)),
+        other => panic!("Expected the stray end tag to be reported, got {:?}", other),
+    }
+    check_well_formed("a.xml", "<?xml version=\"1.0\"?>\n<a><b x=\"1\"/>text</a>\n").unwrap();
+    for xml in ["<a><b></a></b>", "<a>", "<a/><b/>", "<a x=\"1\" x=\"2\"/>", "text<a/>", ""] {
+        assert!(matches!(check_well_formed("a.xml", xml), Err(VfsErr::InvalidSchema { .. })), "{}", xml);
+    }
+    //problems are reported where the offending markup starts
+    for (xml, position) in [
+        ("<a/>\n  <b/>", (2, 3)),
+        ("<a>\n  <b>\n  </c>\n</a>", (3, 3)),
+        ("<a/>\n\n text", (3, 2)),
+        ("<a>\n <b x=\"1\" x=\"2\"/></a>", (2, 2)),
+    ] {
+        match check_well_formed("a.xml", xml) {
+            Err(VfsErr::InvalidSchema { line, column, .. }) => assert_eq!((line, column), position, "{}", xml),
+            other => panic!("Expected {:?} to be rejected, got {:?}", xml, other),
+        }
+    }
+
+    let vfs = Arc::new(MemoryVfs::new("/services"));
+    vfs.insert("123/drafts/dev/schema.xml", r#"<document><pipeline import="a.xml"/></document>"#).unwrap();
+    vfs.insert("123/drafts/dev/a.xml", "<pipeline>\n  <step>\n</pipeline>").unwrap();
+    let opts = PublishOptions {
+        validate_schema: true,
+        ..PublishOptions::default()
+    };
+    match vfs.publish_draft_with(123, "dev", "v1", &opts) {
+        Err(VfsErr::InvalidSchema { file, line, .. }) => assert_eq!((file.as_str(), line), ("a.xml", 3)),
+        other => panic!("Expected a.xml to be rejected, got {:?}", other),
+    }
+    assert!(vfs.list_versions(123).unwrap().iter().all(|v| v.is_draft));
+
+    let bound = BoundVfs::new(DomainOptions::new(123, "dev", true), vfs.clone());
+    assert!(matches!(bound.write_schema_file("a.xml", "<pipeline><step>"), Err(VfsErr::InvalidSchema { .. })));
+    //the files it imports are checked too, ones which don't exist yet are left for publishing
+    match bound.write_schema_file("schema.xml", r#"<document><pipeline import="a.xml"/></document>"#) {
+        Err(VfsErr::InvalidSchema { file, .. }) => assert_eq!(file, "a.xml"),
+        other => panic!("Expected the import a.xml to be rejected, got {:?}", other),
+    }
+    bound.write_schema_file("b.xml", r#"<pipeline import="c.xml"/>"#).unwrap();
+    bound.write_schema_file("a.xml", "<pipeline><step/></pipeline>").unwrap();
+    vfs.insert("123/drafts/dev/b.xml", "<pipeline>").unwrap();
+    let schema = r#"<document><pipeline import="a.xml"/><pipeline import="b.xml"/></document>"#;
+    assert!(matches!(bound.write_schema_file("schema.xml", schema), Err(VfsErr::InvalidSchema { .. })));
+    vfs.insert("123/drafts/dev/b.xml", "<pipeline/>").unwrap();
+    bound.write_schema_file("schema.xml", schema).unwrap();
+    vfs.publish_draft_with(123, "dev", "v1", &opts).unwrap();
+
+    //published versions can't be changed
+    let published = BoundVfs::new(DomainOptions::new(123, "v1", false), vfs.clone());
+    assert!(matches!(published.write_schema_file("a.xml", "<pipeline/>"), Err(VfsErr::VersionIsPublished(_))));
+    assert_eq!(vfs.read_schema_file(123, false, "v1", "a.xml").unwrap(), "<pipeline><step/></pipeline>");
+}
+
+fn module_resolution<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/main.js", b"import {util} from './lib/util'");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export const util = 1");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/date/index.js", b"export const date = 1");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/vendor/lodash/lodash.mjs", b"export default {}");
+    write_file(vfs.as_ref(), "123/drafts/dev/secret.js", b"");
+    write_file(
+        vfs.as_ref(),
+        "123/drafts/dev/ecma/import_map.json",
+        br#"{"imports": {"lodash": "vendor/lodash/lodash.mjs", "lib/": "./lib/", "escape": "../secret.js"}}"#,
+    );
+    let bound = BoundVfs::new(DomainOptions::new(123, "dev", true), vfs.clone());
+    let resolver = bound.ecma_resolver().unwrap();
+    assert_eq!(resolver.resolve("./main.js", None).unwrap(), "main.js");
+    assert_eq!(resolver.resolve("./lib/util", Some("main.js")).unwrap(), "lib/util.js");
+    assert_eq!(resolver.resolve("./date", Some("lib/util.js")).unwrap(), "lib/date/index.js");
+    match resolver.resolve("../main", Some("lib/date/index.js")) {
+        Err(VfsErr::ModuleNotFound { specifier, referrer }) => assert_eq!((specifier.as_str(), referrer.as_deref()), ("../main", Some("lib/date/index.js"))),
+        other => panic!("Expected ../main not to be found, got {:?}", other),
+    }
+    assert_eq!(resolver.resolve("../../main", Some("lib/date/index.js")).unwrap(), "main.js");
+    assert_eq!(resolver.resolve("/lib/util.js", Some("lib/date/index.js")).unwrap(), "lib/util.js");
+    assert_eq!(resolver.resolve("lodash", Some("main.js")).unwrap(), "vendor/lodash/lodash.mjs");
+    assert_eq!(resolver.resolve("lib/date", Some("main.js")).unwrap(), "lib/date/index.js");
+    assert_eq!(resolver.load("lib/util.js").unwrap(), "export const util = 1");
+    assert!(matches!(resolver.resolve("react", Some("main.js")), Err(VfsErr::ModuleNotFound { .. })));
+    assert!(matches!(resolver.resolve("./lib/util.js/index.js", None), Err(VfsErr::ModuleNotFound { .. })));
+    assert!(matches!(resolver.resolve("./lib", None), Err(VfsErr::ModuleNotFound { .. })));
+    assert!(matches!(resolver.resolve("../secret.js", None), Err(VfsErr::DotPathsNotSupported(_))));
+    assert!(matches!(resolver.resolve("../../../secret.js", Some("lib/date/index.js")), Err(VfsErr::DotPathsNotSupported(_))));
+    assert!(matches!(resolver.resolve("escape", None), Err(VfsErr::DotPathsNotSupported(_))));
+
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/import_map.json", b"{\"imports\": []}");
+    assert!(matches!(bound.ecma_resolver(), Err(VfsErr::InvalidImportMap(_))));
+    let bound = BoundVfs::new(DomainOptions::new(123, "other", true), vfs.clone());
+    assert!(bound.ecma_resolver().unwrap().import_map().imports.is_empty());
+}
+
+#[test]
+fn ecma_modules() {
+    for_each_backend!(|vfs| module_resolution(vfs));
+}
+
+fn ecma_manifests<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/main.js", b"import './lib/util.mjs'");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.mjs", b"export {}");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/import_map.json", b"{}");
+    let draft = vfs.ecma_manifest(123, true, "dev").unwrap();
+    assert_eq!(draft.modules.keys().collect::<Vec<_>>(), vec!["lib/util.mjs", "main.js"]);
+    assert_eq!(draft.modules["lib/util.mjs"], EcmaModuleInfo { size: 9, hash: ContentHash::of(b"export {}") });
+    //drafts are rebuilt every time
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.mjs", b"export const a = 1");
+    assert_eq!(vfs.ecma_manifest(123, true, "dev").unwrap().modules["lib/util.mjs"].size, 18);
+
+    vfs.publish_draft(123, "dev", "v1").unwrap();
+    let published: EcmaManifest =
+        serde_json::from_str(&vfs.read_schema_file(123, false, "v1", ECMA_MANIFEST_FILE).unwrap()).unwrap();
+    assert_eq!(published, vfs.ecma_manifest(123, true, "dev").unwrap());
+    assert_eq!(vfs.ecma_manifest(123, false, "v1").unwrap(), published);
+    assert!(vfs.diff_versions(123, &VersionRef::draft("dev"), &VersionRef::version("v1")).unwrap().is_empty());
+
+    //a version published before manifests existed has one built, the version itself is never written to
+    write_file(vfs.as_ref(), "123/versions/v0/ecma/main.js", b"1");
+    let legacy = vfs.ecma_manifest(123, false, "v0").unwrap();
+    assert_eq!(legacy.modules["main.js"].hash, ContentHash::of(b"1"));
+    assert!(!vfs.exists(&vfs.version_dir(123, false, "v0").unwrap().join(ECMA_MANIFEST_FILE).unwrap()).unwrap());
+    assert_eq!(vfs.ecma_manifest(123, false, "v0").unwrap(), legacy);
+    let bound = BoundVfs::new(DomainOptions::new(123, "v0", false), vfs.clone());
+    assert_eq!(bound.ecma_manifest().unwrap(), legacy);
+
+    write_file(vfs.as_ref(), "123/versions/v2/schema.xml", b"<document/>");
+    assert!(vfs.ecma_manifest(123, false, "v2").unwrap().modules.is_empty());
+    assert!(matches!(vfs.ecma_manifest(123, false, "missing"), Err(VfsErr::VersionNotFound(_))));
+}
+
+#[test]
+fn ecma_manifest() {
+    for_each_backend!(|vfs| ecma_manifests(vfs));
+}
+
+///Every change the watcher reports until it's been quiet for a while, by kind, relative to the root
+fn watched_changes<F: Vfs>(vfs: &F, watcher: &Watcher) -> BTreeMap<WatchKind, BTreeSet<String>> {
+    let mut changes: BTreeMap<WatchKind, BTreeSet<String>> = BTreeMap::new();
+    let mut timeout = Duration::from_secs(5);
+    while let Some(event) = watcher.recv_timeout(timeout) {
+        let paths = event.paths.iter().map(|p| p.as_path().strip_prefix(vfs.root()).unwrap().to_string_lossy().to_string());
+        changes.entry(event.kind).or_default().extend(paths);
+        timeout = Duration::from_millis(500);
+    }
+    changes
+}
+
+fn watching<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export {}");
+    let bound = BoundVfs::new(DomainOptions::new(123, "dev", true), vfs.clone());
+    let watcher = bound.watch().unwrap();
+
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", b"<document><apis/></document>");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export const a = 1");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export const a = 2");
+    //other versions aren't watched
+    write_file(vfs.as_ref(), "123/drafts/other/schema.xml", b"<document/>");
+    write_file(vfs.as_ref(), "123/versions/dev/schema.xml", b"<document/>");
+    let changes = watched_changes(vfs.as_ref(), &watcher);
+    assert_eq!(changes.keys().collect::<Vec<_>>(), vec![&WatchKind::Schema, &WatchKind::Ecma]);
+    assert_eq!(changes[&WatchKind::Schema], BTreeSet::from(["123/drafts/dev/schema.xml".to_string()]));
+    assert_eq!(changes[&WatchKind::Ecma], BTreeSet::from(["123/drafts/dev/ecma/lib/util.js".to_string()]));
+
+    //directories created after the watch started
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/new/deep/mod.js", b"export {}");
+    write_file(vfs.as_ref(), "123/files/logo.png", b"png");
+    write_file(vfs.as_ref(), "123/plugins/auth.wasm", b"wasm");
+    write_file(vfs.as_ref(), "456/files/logo.png", b"png");
+    let changes = watched_changes(vfs.as_ref(), &watcher);
+    assert_eq!(changes[&WatchKind::Ecma], BTreeSet::from(["123/drafts/dev/ecma/new/deep/mod.js".to_string()]));
+    assert_eq!(changes[&WatchKind::Resource], BTreeSet::from(["123/files/logo.png".to_string()]));
+    assert_eq!(changes[&WatchKind::Plugin], BTreeSet::from(["123/plugins/auth.wasm".to_string()]));
+    assert!(!changes.contains_key(&WatchKind::Schema));
+
+    vfs.remove_file(&vfs.resolve("123/drafts/dev/ecma/lib/util.js").unwrap()).unwrap();
+    let changes = watched_changes(vfs.as_ref(), &watcher);
+    assert_eq!(changes[&WatchKind::Ecma], BTreeSet::from(["123/drafts/dev/ecma/lib/util.js".to_string()]));
+    assert!(watcher.try_recv().is_none());
+}
+
+#[test]
+fn watch_for_changes() {
+    for_each_backend!(|vfs| watching(vfs));
+}
+
+fn whole_files<F: Vfs>(vfs: Arc<F>) {
+    let big: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+    write_file(vfs.as_ref(), "123/files/big.bin", &big);
+    write_file(vfs.as_ref(), "123/files/small.txt", b"small");
+    write_file(vfs.as_ref(), "123/files/empty.txt", b"");
+    write_file(vfs.as_ref(), "123/drafts/dev/schema.xml", "<document>é</document>".as_bytes());
+    assert_eq!(vfs.read_bytes(vfs.resolve("123/files/big.bin").unwrap()).unwrap(), big);
+    assert_eq!(vfs.read_resource_bytes(123, "small.txt").unwrap(), "small");
+    assert!(vfs.read_resource_bytes(123, "empty.txt").unwrap().is_empty());
+    assert_eq!(vfs.read_schema_file(123, true, "dev", "schema.xml").unwrap(), "<document>é</document>");
+    let bound = BoundVfs::new(DomainOptions::new(123, "dev", true), vfs.clone());
+    assert_eq!(bound.read_resource_bytes(PathBuf::from("./small.txt")).unwrap(), "small");
+    assert!(vfs.read_resource_bytes(123, "missing.txt").is_err());
+    assert!(bound.read_resource_bytes(PathBuf::from("../drafts/dev/schema.xml")).is_err());
+}
+
+#[test]
+fn read_bytes() {
+    for_each_backend!(|vfs| whole_files(vfs));
+    let dir = tempfile::tempdir().unwrap();
+    whole_files(Arc::new(FilesystemVfs::new(dir.path().to_string_lossy().to_string()).with_mmap_threshold(1)));
+    let dir = tempfile::tempdir().unwrap();
+    whole_files(Arc::new(FilesystemVfs::new(dir.path().to_string_lossy().to_string()).with_mmap_threshold(MMAP_THRESHOLD)));
+
+    //memory files are handed out without copying
+    let vfs = MemoryVfs::new("/services");
+    let path = vfs.insert("123/files/logo.png", vec![1u8; 1024]).unwrap();
+    let a = vfs.read_bytes(path.clone()).unwrap();
+    let b = vfs.read_bytes(path).unwrap();
+    assert_eq!(a.as_ptr(), b.as_ptr());
+}
+
+fn stat<F: Vfs>(vfs: Arc<F>) {
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/lib/util.js", b"export {}");
+    write_file(vfs.as_ref(), "123/drafts/dev/ecma/main.js", b"import './lib/util.js'");
+    let file = vfs.resolve("123/drafts/dev/ecma/lib/util.js").unwrap();
+    let meta = vfs.metadata(&file).unwrap();
+    assert_eq!(meta.kind(), VfsFileKind::File);
+    assert!(meta.is_file() && !meta.is_dir() && !meta.readonly());
+    assert_eq!(meta.len(), 9);
+    assert!(meta.modified().is_some());
+    let dir = vfs.resolve("123/drafts/dev/ecma/lib").unwrap();
+    assert_eq!(vfs.metadata(&dir).unwrap().kind(), VfsFileKind::Dir);
+
+    assert!(vfs.exists(&file).unwrap());
+    assert!(vfs.exists(&dir).unwrap());
+    assert!(!vfs.exists(&vfs.resolve("123/drafts/dev/ecma/lib/missing.js").unwrap()).unwrap());
+    assert!(!vfs.exists(&vfs.resolve("456/drafts/dev").unwrap()).unwrap());
+    //a parent which is a file
+    assert!(!vfs.exists(&file.join("index.js").unwrap()).unwrap());
+
+    let files: BTreeSet<String> = vfs
+        .read_ecma(123, true, "dev")
+        .unwrap()
+        .map(|entry| entry.unwrap().rel.to_string_lossy().to_string())
+        .collect();
+    assert_eq!(files, BTreeSet::from(["lib/util.js".to_string(), "main.js".to_string()]));
+}
+
+#[test]
+fn metadata_and_exists() {
+    for_each_backend!(|vfs| stat(vfs));
+
+    let dir = tempfile::tempdir().unwrap();
+    let vfs = FilesystemVfs::new(dir.path().to_string_lossy().to_string());
+    write_file(&vfs, "123/drafts/dev/ecma/main.js", b"");
+    let file = vfs.resolve("123/drafts/dev/ecma/main.js").unwrap();
+    let mut permissions = fs::metadata(file.as_path()).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(file.as_path(), permissions).unwrap();
+    assert!(vfs.metadata(&file).unwrap().readonly());
+}
+
+fn mutations<F: Vfs>(vfs: Arc<F>) {
+    let files = vfs.resource_dir(123).unwrap();
+    let dir = files.join("docs").unwrap();
+    vfs.create_dir(&dir).unwrap();
+    assert!(vfs.metadata(&dir).unwrap().is_dir());
+    assert!(vfs.create_dir(&dir).is_err());
+    assert!(vfs.create_dir(&files.join("a/b").unwrap()).is_err());
+    //creating a file where a directory already is fails and leaves the directory alone
+    let mut opts = VfsOpenOptions::new();
+    opts.write(true).create(true);
+    match vfs.open_with(dir.clone(), opts) {
+        Err(VfsErr::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::IsADirectory),
+        other => panic!("expected IsADirectory, got {:?}", other.map(|_| ())),
+    }
+    assert!(vfs.metadata(&dir).unwrap().is_dir());
+    write_file(vfs.as_ref(), "123/files/docs/readme.txt", b"read me");
+
+    let copy = files.join("docs/copy.txt").unwrap();
+    assert_eq!(vfs.copy(&dir.join("readme.txt").unwrap(), &copy).unwrap(), 7);
+    assert_eq!(read_all(vfs.as_ref(), &copy), b"read me");
+    //replaces an existing file, the source is untouched
+    write_file(vfs.as_ref(), "123/files/docs/other.txt", b"other");
+    vfs.copy(&vfs.resolve("123/files/docs/other.txt").unwrap(), &copy).unwrap();
+    assert_eq!(read_all(vfs.as_ref(), &copy), b"other");
+    assert_eq!(read_all(vfs.as_ref(), &dir.join("readme.txt").unwrap()), b"read me");
+    assert!(vfs.copy(&files.join("missing.txt").unwrap(), &copy).is_err());
+    assert!(vfs.copy(&copy, &dir).is_err());
+
+    let bound = BoundVfs::new(DomainOptions::new(123, "dev", true), vfs.clone());
+    let other = BoundVfs::new(DomainOptions::new(456, "dev", true), vfs.clone());
+    let moved = bound.resolve("files/moved").unwrap();
+    assert_eq!(moved, files.join("moved").unwrap());
+    match other.rename(&dir, &other.resolve("files/stolen").unwrap()) {
+        Err(VfsErr::ServiceMismatch(_)) => {}
+        res => panic!("Expected a service mismatch, got {:?}", res),
+    }
+    match other.copy(&copy, &other.resolve("files/copy.txt").unwrap()) {
+        Err(VfsErr::ServiceMismatch(_)) => {}
+        res => panic!("Expected a service mismatch, got {:?}", res),
+    }
+    //domain files don't belong to any service
+    assert!(matches!(bound.remove_file(&vfs.domain_file("dev.hypi.ai").unwrap()), Err(VfsErr::ServiceMismatch(_))));
+    assert!(matches!(other.remove_dir_all(&dir), Err(VfsErr::ServiceMismatch(_))));
+    assert!(matches!(other.create_dir(&files.join("new").unwrap()), Err(VfsErr::ServiceMismatch(_))));
+    assert!(bound.resolve("../456/files").is_err());
+
+    bound.rename(&dir, &moved).unwrap();
+    assert!(!vfs.exists(&dir).unwrap());
+    bound.copy(&moved.join("copy.txt").unwrap(), &bound.resolve("files/top.txt").unwrap()).unwrap();
+    bound.remove_file(&moved.join("copy.txt").unwrap()).unwrap();
+    assert!(!vfs.exists(&moved.join("copy.txt").unwrap()).unwrap());
+    bound.create_dir(&moved.join("empty").unwrap()).unwrap();
+    bound.remove_dir_all(&moved).unwrap();
+    assert!(!vfs.exists(&moved).unwrap());
+    assert_eq!(read_all(vfs.as_ref(), &files.join("top.txt").unwrap()), b"other");
+}
+
+#[test]
+fn mutation_primitives() {
+    for_each_backend!(|vfs| mutations(vfs));
+}
+
+fn walk<F: Vfs>(vfs: Arc<F>) {
+    for (path, data) in [
+        ("123/files/b.txt", "b"),
+        ("123/files/a/z.txt", "z"),
+        ("123/files/a/c/d.txt", "dd"),
+        ("123/files/a.txt", "aaa"),
+        ("123/files/lib/x.js", "x"),
+        ("123/files/lib2/y.js", "y"),
+        ("123/files/a/b.txt", "bb"),
+    ] {
+        write_file(vfs.as_ref(), path, data.as_bytes());
+    }
+    vfs.create_dir(&vfs.resolve("123/files/empty").unwrap()).unwrap();
+    let files = vfs.resource_dir(123).unwrap();
+    let walked: Vec<(String, u64)> = vfs
+        .dir_stream(files.clone())
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            assert!(entry.metadata.is_file());
+            assert_eq!(entry.path, files.join(entry.rel.to_str().unwrap()).unwrap());
+            (entry.rel.to_string_lossy().to_string(), entry.metadata.len())
+        })
+        .collect();
+    let expected = [
+        ("a/b.txt", 2),
+        ("a/c/d.txt", 2),
+        ("a/z.txt", 1),
+        ("a.txt", 3),
+        ("b.txt", 1),
+        ("lib/x.js", 1),
+        ("lib2/y.js", 1),
+    ];
+    assert_eq!(walked, expected.map(|(rel, len)| (rel.to_string(), len)));
+
+    let shallow: Vec<_> = vfs
+        .dir_stream(files.clone())
+        .unwrap()
+        .with_max_depth(1)
+        .map(|entry| entry.unwrap().rel.to_string_lossy().to_string())
+        .collect();
+    assert_eq!(shallow, ["a/b.txt", "a/z.txt", "a.txt", "b.txt", "lib/x.js", "lib2/y.js"]);
+    let top: Vec<_> = vfs
+        .dir_stream(files.clone())
+        .unwrap()
+        .with_max_depth(0)
+        .map(|entry| entry.unwrap().rel.to_string_lossy().to_string())
+        .collect();
+    assert_eq!(top, ["a.txt", "b.txt"]);
+
+    //only the immediate children, directories included
+    let mut children: Vec<_> = vfs.read_dir(&files.join("lib").unwrap()).unwrap().collect();
+    children.sort();
+    assert_eq!(children, [files.join("lib/x.js").unwrap()]);
+    let mut children: Vec<_> = vfs.read_dir(&files).unwrap().collect();
+    children.sort();
+    let expected = ["a", "a.txt", "b.txt", "empty", "lib", "lib2"].map(|name| files.join(name).unwrap());
+    assert_eq!(children, expected);
+}
+
+#[test]
+fn dir_stream_order() {
+    for_each_backend!(|vfs| walk(vfs));
+
+    //deep trees don't grow the stack
+    let vfs = MemoryVfs::new("/services");
+    let deep = (0..2000).map(|i| format!("d{}", i)).collect::<Vec<_>>().join("/");
+    vfs.insert(format!("123/files/{}/leaf.txt", deep).as_str(), "leaf").unwrap();
+    let entries: Vec<_> = vfs.dir_stream(vfs.resource_dir(123).unwrap()).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].as_ref().unwrap().rel, PathBuf::from(format!("{}/leaf.txt", deep)));
+}